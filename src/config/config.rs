@@ -7,6 +7,34 @@ pub struct Config {
     pub danmaku_speed: Option<u64>,
     pub font_alpha: Option<f64>,
     pub font_scale: Option<f64>,
+    pub quality: Option<String>,
+    pub codec_preference: Option<Vec<String>>,
+    // seconds of the stream kept on disk for clips, off by default. it takes the bitrate times this, capped at an hour
+    pub replay_buffer: Option<u64>,
+    pub timeshift: Option<String>,
+    pub http_token: Option<String>,
+    // tables go last
+    pub record: Option<RecordConfig>,
+    pub restream: Option<RestreamConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RecordConfig {
+    pub output_dir: Option<String>,
+    pub filename_template: Option<String>,
+    // shell commands run when a recording starts, splits and finishes,
+    // DML_DANMAKU_FILE is an .ass copy of the danmaku track of DML_FILE
+    pub on_start: Option<String>,
+    pub on_split: Option<String>,
+    pub on_finish: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RestreamConfig {
+    // used to burn in danmaku, e.g. "libx264" and "veryfast", and "aac" or "libopus" for the audio
+    pub encoder: Option<String>,
+    pub preset: Option<String>,
+    pub audio_encoder: Option<String>,
 }
 
 pub fn load_config(j: &str) -> Result<Config, std::io::Error> {
@@ -16,7 +44,7 @@ pub fn load_config(j: &str) -> Result<Config, std::io::Error> {
 
 pub enum BVideoType {
     Video,
-    Bangumi
+    Bangumi,
 }
 
 pub struct BVideoInfo {
@@ -1,11 +1,14 @@
 pub mod config;
 
-use self::config::{BVideoInfo, BVideoType, Config};
-use crate::utils::is_android;
+use self::config::{BVideoInfo, BVideoType, Config, RecordConfig, RestreamConfig};
+use crate::metrics::Metrics;
+use crate::utils::{
+    is_android, normalize_codec, parse_duration_secs, parse_size_bytes, sanitize_filename, truncate_bytes,
+};
 use crate::Args;
 use reqwest::Url;
 use std::cell::{Cell, RefCell};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::{fs::OpenOptions, io::AsyncWriteExt};
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -17,6 +20,15 @@ pub enum Platform {
 pub enum RunMode {
     Play,
     Record,
+    PlayRecord,
+    Restream,
+    Serve,
+}
+
+impl RunMode {
+    pub fn records(&self) -> bool {
+        matches!(self, RunMode::Record | RunMode::PlayRecord)
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -26,6 +38,59 @@ pub enum StreamType {
     DASH,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Quality {
+    Best,
+    Worst,
+    Source,
+    Height(u64, u64), // height, fps (0 for any)
+}
+
+impl Quality {
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().to_lowercase();
+        match s.as_str() {
+            "best" => return Some(Quality::Best),
+            "worst" => return Some(Quality::Worst),
+            "source" | "origin" => return Some(Quality::Source),
+            _ => {}
+        }
+        let (h, fps) = match s.split_once('p') {
+            Some((h, fps)) => (h, if fps.is_empty() { "0" } else { fps }),
+            None => (s.as_str(), "0"),
+        };
+        Some(Quality::Height(h.parse().ok()?, fps.parse().ok()?))
+    }
+
+    pub fn label(&self) -> String {
+        match *self {
+            Quality::Best => "best".into(),
+            Quality::Worst => "worst".into(),
+            Quality::Source => "source".into(),
+            Quality::Height(h, 0) => format!("{}p", h),
+            Quality::Height(h, fps) => format!("{}p{}", h, fps),
+        }
+    }
+
+    /// Picks from items described by (height, fps), the first one wins a tie and source counts as best.
+    pub fn pick<'a, T>(&self, items: &'a [T], key: impl Fn(&T) -> (u64, u64)) -> Option<&'a T> {
+        let worst = || items.iter().min_by_key(|it| key(it));
+        match *self {
+            Quality::Best | Quality::Source => items.iter().rev().max_by_key(|it| key(it)),
+            Quality::Worst => worst(),
+            Quality::Height(h, fps) => items
+                .iter()
+                .rev()
+                .filter(|it| {
+                    let (ih, ifps) = key(it);
+                    ih <= h && (fps == 0 || ifps <= fps)
+                })
+                .max_by_key(|it| key(it))
+                .or_else(worst),
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Site {
     BiliLive,
@@ -36,12 +101,47 @@ pub enum Site {
     YoutubeLive,
 }
 
+impl Site {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Site::BiliLive | Site::BiliVideo => "bilibili",
+            Site::DouyuLive => "douyu",
+            Site::HuyaLive => "huya",
+            Site::TwitchLive => "twitch",
+            Site::YoutubeLive => "youtube",
+        }
+    }
+}
+
+const DEFAULT_FILENAME_TEMPLATE: &str = "{title} - {date} {time} - {part}";
+const MAX_FILENAME_LEN: usize = 240; // in bytes, most filesystems allow 255
+const MAX_REPLAY_BUFFER: u64 = 3600; // in seconds
+const MAX_TITLE_LEN: usize = 180; // in bytes
+
+// cache time when only a size is given, mpv then goes back as far as the size allows
+const TIMESHIFT_ANY_SECS: u64 = u32::MAX as u64;
+// cache size per second when only a duration is given, high enough for 1080p60
+const TIMESHIFT_BYTES_PER_SEC: u64 = 2 << 20;
+
 pub struct ConfigManager {
     pub plat: Platform,
     pub bcookie: String,
     pub cookies_from_browser: String,
     pub plive: bool,
     pub quiet: bool,
+    pub audio_only: bool,
+    pub from_start: bool,
+    pub rewind: Cell<u64>, // in seconds
+    pub quality: Cell<Quality>,
+    quality_conf: Option<String>,
+    pub codec_preference: Vec<String>,
+    codec_excluded: RefCell<Vec<String>>,
+    pub replay_buffer: u64, // in seconds, 0 for disabled
+    replay_buffer_conf: Option<u64>,
+    pub timeshift: Option<(u64, u64)>, // in seconds and bytes
+    timeshift_conf: Option<String>,
+    pub split_every: u64, // in seconds, 0 for never
+    pub split_size: u64,  // in bytes, 0 for never
     pub wait_interval: u64,
     pub font_scale: Cell<f64>,
     pub font_alpha: Cell<f64>,
@@ -49,11 +149,31 @@ pub struct ConfigManager {
     pub display_fps: Cell<(u64, u64)>,
     pub room_url: String,
     pub http_address: Option<String>,
+    pub http_token: Option<String>,
+    http_token_conf: Option<String>,
+    pub http_hls: bool,
+    pub metrics_address: Option<String>,
+    pub metrics: Metrics,
     pub run_mode: RunMode,
     pub site: Site,
     pub stream_type: Cell<StreamType>,
     pub bvideo_info: RefCell<BVideoInfo>,
     pub title: RefCell<String>,
+    pub title_history: RefCell<Vec<(chrono::DateTime<chrono::Local>, String)>>,
+    pub streamer: RefCell<String>,
+    pub cover_url: RefCell<String>,
+    pub output_dir: PathBuf,
+    pub filename_template: String,
+    pub on_record_start: Option<String>,
+    pub on_record_split: Option<String>,
+    pub on_record_finish: Option<String>,
+    record_conf: Option<RecordConfig>,
+    pub output: Option<String>,
+    pub burn_danmaku: bool,
+    pub burn_encoder: String,
+    pub burn_preset: String,
+    pub burn_audio_encoder: String,
+    restream_conf: Option<RestreamConfig>,
     on_writing: Cell<bool>,
 }
 
@@ -72,7 +192,7 @@ impl ConfigManager {
         let c = std::fs::read(config_path).unwrap();
         let c = String::from_utf8_lossy(&c);
         let c = config::load_config(&c).unwrap();
-        let room_url = args.url.clone();
+        let room_url = args.url.clone().unwrap_or_default();
         let site = if room_url.contains("live.bilibili.com/") {
             Site::BiliLive
         } else if room_url.contains("bilibili.com/") {
@@ -102,11 +222,43 @@ impl ConfigManager {
         } else {
             panic!("unknown url")
         };
-        let run_mode = if args.record || args.http_address.is_some() {
+        let run_mode = if args.http_address.is_some() {
+            RunMode::Serve
+        } else if args.output.is_some() {
+            RunMode::Restream
+        } else if args.play_record {
+            RunMode::PlayRecord
+        } else if args.record {
             RunMode::Record
         } else {
             RunMode::Play
         };
+        let from_start = args.from_start || run_mode.records();
+        let quality = args
+            .quality
+            .as_ref()
+            .or(c.quality.as_ref())
+            .map(|it| Quality::parse(it).expect("invalid quality"))
+            .unwrap_or(if args.audio_only { Quality::Worst } else { Quality::Best });
+        // a recording has everything already
+        let replay_buffer = match run_mode {
+            RunMode::Play => c.replay_buffer.unwrap_or(0).min(MAX_REPLAY_BUFFER),
+            RunMode::Record | RunMode::PlayRecord | RunMode::Restream | RunMode::Serve => 0,
+        };
+        let timeshift = match run_mode {
+            RunMode::Play | RunMode::PlayRecord => args.timeshift.as_ref().or(c.timeshift.as_ref()).map(|it| {
+                Self::parse_timeshift(it)
+                    .expect("invalid timeshift, use a duration like \"30m\" or a size like \"2GB\"")
+            }),
+            RunMode::Record | RunMode::Restream | RunMode::Serve => None,
+        };
+        let output_dir = match c.record.as_ref().and_then(|it| it.output_dir.as_ref()) {
+            Some(it) => match (it.strip_prefix("~/"), directories::BaseDirs::new()) {
+                (Some(rest), Some(d)) => d.home_dir().join(rest),
+                _ => PathBuf::from(it),
+            },
+            None => PathBuf::from("."),
+        };
         Self {
             room_url: room_url.replace("dmlive://", "https://"),
             stream_type: Cell::new(StreamType::FLV),
@@ -118,14 +270,92 @@ impl ConfigManager {
             bvideo_info: RefCell::new(bvinfo),
             bcookie: c.bcookie.unwrap_or_else(|| "".into()),
             http_address: args.http_address.as_ref().map(|it| it.into()),
+            http_token: args.http_token.clone().or(c.http_token.clone()).filter(|it| !it.is_empty()),
+            http_token_conf: c.http_token,
+            http_hls: args.http_hls,
+            metrics_address: args.metrics_address.clone(),
+            metrics: Metrics::default(),
             plive: args.plive,
             quiet: args.quiet,
+            audio_only: args.audio_only,
+            from_start,
+            rewind: Cell::new(
+                args.rewind.as_ref().map(|it| parse_duration_secs(it).expect("invalid rewind duration")).unwrap_or(0),
+            ),
+            quality: Cell::new(quality),
+            quality_conf: c.quality,
+            codec_preference: c
+                .codec_preference
+                .unwrap_or_default()
+                .iter()
+                .map(|it| normalize_codec(it).to_string())
+                .filter(|it| !it.is_empty())
+                .collect(),
+            codec_excluded: RefCell::new(Vec::new()),
+            replay_buffer,
+            replay_buffer_conf: c.replay_buffer,
+            timeshift,
+            timeshift_conf: c.timeshift,
+            split_every: args
+                .split_every
+                .as_ref()
+                .map(|it| parse_duration_secs(it).expect("invalid split duration"))
+                .unwrap_or(0),
+            split_size: args
+                .split_size
+                .as_ref()
+                .map(|it| parse_size_bytes(it).expect("invalid split size"))
+                .unwrap_or(0),
             wait_interval: args.wait_interval.unwrap_or(0),
             on_writing: Cell::new(false),
             plat,
             cookies_from_browser: c.cookies_from_browser.unwrap_or_else(|| "".into()),
             display_fps: Cell::new((60, 0)),
             title: RefCell::new("".to_string()),
+            title_history: RefCell::new(Vec::new()),
+            streamer: RefCell::new("".to_string()),
+            cover_url: RefCell::new("".to_string()),
+            output_dir,
+            filename_template: c
+                .record
+                .as_ref()
+                .and_then(|it| it.filename_template.clone())
+                .unwrap_or_else(|| DEFAULT_FILENAME_TEMPLATE.into()),
+            on_record_start: c.record.as_ref().and_then(|it| it.on_start.clone()),
+            on_record_split: c.record.as_ref().and_then(|it| it.on_split.clone()),
+            on_record_finish: c.record.as_ref().and_then(|it| it.on_finish.clone()),
+            record_conf: c.record,
+            output: args.output.clone(),
+            burn_danmaku: args.burn_danmaku && !args.audio_only,
+            burn_encoder: c.restream.as_ref().and_then(|it| it.encoder.clone()).unwrap_or_else(|| "libx264".into()),
+            burn_preset: c.restream.as_ref().and_then(|it| it.preset.clone()).unwrap_or_else(|| "veryfast".into()),
+            burn_audio_encoder: c
+                .restream
+                .as_ref()
+                .and_then(|it| it.audio_encoder.clone())
+                .unwrap_or_else(|| "aac".into()),
+            restream_conf: c.restream,
+        }
+    }
+
+    fn parse_timeshift(s: &str) -> Option<(u64, u64)> {
+        let mut secs = 0;
+        let mut bytes = 0;
+        for it in s.split(',') {
+            // "10m" is a duration, sizes need the "B"
+            if it.trim().to_uppercase().ends_with('B') {
+                bytes = parse_size_bytes(it)?;
+            } else {
+                secs = parse_duration_secs(it)?;
+            }
+        }
+        // a longer duration than the sentinel means the same thing anyway
+        let secs = secs.min(TIMESHIFT_ANY_SECS);
+        match (secs, bytes) {
+            (0, 0) => None,
+            (0, b) => Some((TIMESHIFT_ANY_SECS, b)),
+            (s, 0) => Some((s, s.saturating_mul(TIMESHIFT_BYTES_PER_SEC))),
+            it => Some(it),
         }
     }
 
@@ -153,6 +383,92 @@ impl ConfigManager {
         }
     }
 
+    pub fn set_title(&self, title: &str) {
+        if self.title.borrow().eq(title) {
+            return;
+        }
+        self.title_history.borrow_mut().push((chrono::Local::now(), title.to_string()));
+        self.title.borrow_mut().clear();
+        self.title.borrow_mut().push_str(title);
+    }
+
+    /// The muxer for `--output`, none for files so ffmpeg picks it from the extension.
+    pub fn output_format(&self) -> Option<&'static str> {
+        let scheme = self.output.as_deref()?.split_once("://")?.0;
+        match scheme {
+            "rtmp" | "rtmps" => Some("flv"),
+            "srt" | "udp" | "rtp" | "tcp" => Some("mpegts"),
+            _ => None,
+        }
+    }
+
+    pub fn room_id(&self) -> String {
+        let u = match Url::parse(&self.room_url) {
+            Ok(it) => it,
+            Err(_) => return "".into(),
+        };
+        if let Some((_, v)) = u.query_pairs().find(|it| it.0.eq("v")) {
+            return v.to_string();
+        }
+        u.path_segments().and_then(|mut it| it.rfind(|it| !it.is_empty())).unwrap_or("").to_string()
+    }
+
+    /// Renders the record filename template, `part` is the part number or what the file is, like "clip".
+    pub fn record_filename(&self, time: &chrono::DateTime<chrono::Local>, part: &str) -> PathBuf {
+        let title = self.title.borrow();
+        let streamer = self.streamer.borrow();
+        let streamer = if streamer.is_empty() {
+            self.room_id()
+        } else {
+            streamer.to_string()
+        };
+        let values = [
+            ("{site}", self.site.name().to_string()),
+            ("{room_id}", self.room_id()),
+            ("{streamer}", streamer),
+            ("{title}", truncate_bytes(&title, MAX_TITLE_LEN).to_string()),
+            ("{date}", time.format("%F").to_string()),
+            ("{time}", time.format("%H-%M-%S").to_string()),
+            ("{part}", part.to_string()),
+            ("{quality}", self.quality.get().label()),
+        ];
+        let mut ret = self.output_dir.clone();
+        let template = self.filename_template.trim_end_matches(".mkv");
+        // a "/" in the template makes sub directories, one in a value does not
+        let mut components = template.split('/').filter(|it| !it.is_empty()).peekable();
+        while let Some(component) = components.next() {
+            let mut name = component.to_string();
+            for (k, v) in values.iter() {
+                name = name.replace(k, &sanitize_filename(v));
+            }
+            let name = truncate_bytes(&sanitize_filename(&name), MAX_FILENAME_LEN).to_string();
+            if components.peek().is_none() {
+                ret.push(format!("{}.mkv", name));
+            } else {
+                ret.push(name);
+            }
+        }
+        ret
+    }
+
+    /// Lower is preferred, codecs that failed to decode come last.
+    pub fn codec_rank(&self, codec: &str) -> usize {
+        let c = normalize_codec(codec);
+        if self.codec_excluded.borrow().iter().any(|it| it.eq(c)) {
+            return usize::MAX;
+        }
+        self.codec_preference.iter().position(|it| it.eq(c)).unwrap_or(self.codec_preference.len())
+    }
+
+    pub fn exclude_codec(&self, codec: &str) -> bool {
+        let c = normalize_codec(codec);
+        if c.is_empty() || self.codec_excluded.borrow().iter().any(|it| it.eq(c)) {
+            return false;
+        }
+        self.codec_excluded.borrow_mut().push(c.to_string());
+        true
+    }
+
     pub async fn write_config(&self) -> anyhow::Result<()> {
         if !self.on_writing.get() {
             self.on_writing.set(true);
@@ -172,6 +488,13 @@ impl ConfigManager {
                     danmaku_speed: Some(self.danmaku_speed.get()),
                     font_alpha: Some(self.font_alpha.get()),
                     font_scale: Some(self.font_scale.get()),
+                    quality: self.quality_conf.clone(),
+                    codec_preference: Some(self.codec_preference.clone()).filter(|it| !it.is_empty()),
+                    replay_buffer: self.replay_buffer_conf,
+                    timeshift: self.timeshift_conf.clone(),
+                    http_token: self.http_token_conf.clone(),
+                    record: self.record_conf.clone(),
+                    restream: self.restream_conf.clone(),
                 })
                 .unwrap()
                 .as_bytes(),
@@ -183,3 +506,58 @@ impl ConfigManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quality() {
+        assert_eq!(Quality::parse("best"), Some(Quality::Best));
+        assert_eq!(Quality::parse(" Worst "), Some(Quality::Worst));
+        assert_eq!(Quality::parse("origin"), Some(Quality::Source));
+        assert_eq!(Quality::parse("1080p60"), Some(Quality::Height(1080, 60)));
+        assert_eq!(Quality::parse("720P"), Some(Quality::Height(720, 0)));
+        assert_eq!(Quality::parse("480"), Some(Quality::Height(480, 0)));
+        assert_eq!(Quality::parse(""), None);
+        assert_eq!(Quality::parse("hd"), None);
+        assert_eq!(Quality::parse("1080p60fps"), None);
+        for it in ["best", "source", "720p", "1080p60"] {
+            assert_eq!(Quality::parse(it).unwrap().label(), it);
+        }
+    }
+
+    #[test]
+    fn picks_quality() {
+        // (height, fps), in the order a site lists them
+        let items = [(720, 30), (1080, 60), (1080, 30), (480, 30), (1080, 60)];
+        let pick = |q: &str| {
+            let it = Quality::parse(q).unwrap().pick(&items, |it| *it).unwrap();
+            // the index tells which of the equal ones won
+            items.iter().position(|x| std::ptr::eq(x, it)).unwrap()
+        };
+        assert_eq!(pick("best"), 1);
+        assert_eq!(pick("source"), 1);
+        assert_eq!(pick("worst"), 3);
+        assert_eq!(pick("1080p30"), 2);
+        assert_eq!(pick("1080p"), 1);
+        assert_eq!(pick("900p"), 0);
+        // nothing small enough falls back to the smallest
+        assert_eq!(pick("360p"), 3);
+        assert!(Quality::Best.pick(&[] as &[(u64, u64)], |it| *it).is_none());
+    }
+
+    #[test]
+    fn parses_timeshift() {
+        let parse = ConfigManager::parse_timeshift;
+        assert_eq!(parse("30m,1GB"), Some((1800, 1 << 30)));
+        assert_eq!(parse("1GB"), Some((TIMESHIFT_ANY_SECS, 1 << 30)));
+        assert_eq!(parse("10s"), Some((10, 10 * TIMESHIFT_BYTES_PER_SEC)));
+        assert_eq!(parse("0s"), None);
+        assert_eq!(parse("soon"), None);
+        // huge durations stay usable instead of overflowing the size
+        let (secs, bytes) = parse("99999999999s").unwrap();
+        assert_eq!(secs, TIMESHIFT_ANY_SECS);
+        assert_eq!(bytes, TIMESHIFT_ANY_SECS * TIMESHIFT_BYTES_PER_SEC);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    config::{ConfigManager, Quality},
+    dmlerr,
+    dmlive::DMLMessage,
+    ipcmanager::IPCManager,
+};
+use anyhow::Result;
+use futures::StreamExt;
+use log::info;
+use serde_json::{json, Value};
+use std::{path::PathBuf, rc::Rc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+/// Takes json commands like `{"cmd": "quality", "args": ["720p"]}` on a unix socket next to the other ipc sockets.
+#[allow(unused)]
+pub struct ControlServer {
+    ipc_manager: Rc<IPCManager>,
+    cm: Rc<ConfigManager>,
+    mtx: async_channel::Sender<DMLMessage>,
+}
+
+impl ControlServer {
+    pub fn new(cm: Rc<ConfigManager>, im: Rc<IPCManager>, mtx: async_channel::Sender<DMLMessage>) -> Self {
+        Self {
+            ipc_manager: im,
+            cm,
+            mtx,
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let listener = UnixListener::bind(self.ipc_manager.get_ctl_socket_path())?;
+        let mut tasks = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(_) = tasks.next() => {},
+                it = listener.accept() => {
+                    let (s, _) = it?;
+                    tasks.push(async move {
+                        let _ = self.serve(s).await.map_err(|e| info!("control client error: {}", e));
+                    });
+                }
+            }
+        }
+    }
+
+    // one json request per line, one json response per line
+    async fn serve(&self, s: UnixStream) -> Result<()> {
+        let (r, mut w) = s.into_split();
+        let mut lines = BufReader::new(r).lines();
+        while let Some(line) = lines.next_line().await? {
+            let resp = match serde_json::from_str::<Value>(&line) {
+                Ok(it) => self.handle(&it).await,
+                Err(e) => json!({"ok": false, "error": e.to_string()}),
+            };
+            w.write_all(format!("{}\n", resp).as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn handle(&self, req: &Value) -> Value {
+        match self.run_command(req).await {
+            Ok(Value::Null) => json!({"ok": true}),
+            Ok(it) => json!({"ok": true, "result": it}),
+            Err(e) => json!({"ok": false, "error": e.to_string()}),
+        }
+    }
+
+    fn status(&self) -> Value {
+        let mode = match self.cm.run_mode {
+            crate::config::RunMode::Play => "play",
+            crate::config::RunMode::Record => "record",
+            crate::config::RunMode::PlayRecord => "play-record",
+            crate::config::RunMode::Restream => "restream",
+            crate::config::RunMode::Serve => "serve",
+        };
+        json!({
+            "site": self.cm.site.name(),
+            "room_url": self.cm.room_url,
+            "room_id": self.cm.room_id(),
+            "streamer": *self.cm.streamer.borrow(),
+            "title": *self.cm.title.borrow(),
+            "mode": mode,
+            "quality": self.cm.quality.get().label(),
+            "font_scale": self.cm.font_scale.get(),
+            "font_alpha": self.cm.font_alpha.get(),
+            "danmaku_speed": self.cm.danmaku_speed.get(),
+            "page": self.cm.bvideo_info.borrow().current_page,
+        })
+    }
+
+    async fn send(&self, msg: DMLMessage) -> Result<Value> {
+        self.mtx.send(msg).await.map_err(|_| dmlerr!())?;
+        Ok(Value::Null)
+    }
+
+    async fn run_command(&self, req: &Value) -> Result<Value> {
+        let cmd = req.pointer("/cmd").and_then(|it| it.as_str()).ok_or_else(|| anyhow::anyhow!("no cmd"))?;
+        let args: Vec<&str> = req
+            .pointer("/args")
+            .and_then(|it| it.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|it| it.as_str())
+            .collect();
+        let arg = || args.first().copied().ok_or_else(|| anyhow::anyhow!("{} needs an argument", cmd));
+        let bili_video = matches!(self.cm.site, crate::config::Site::BiliVideo);
+        match cmd {
+            "status" => Ok(self.status()),
+            "reload" if bili_video => self.send(DMLMessage::PlayVideo).await,
+            "reload" => self.send(DMLMessage::RequestRestart).await,
+            "quality" => {
+                let q = Quality::parse(arg()?).ok_or_else(|| anyhow::anyhow!("invalid quality"))?;
+                self.send(DMLMessage::SetQuality(q)).await
+            }
+            "font-scale" => self.send(DMLMessage::SetFontScale(arg()?.parse()?)).await,
+            "font-alpha" => self.send(DMLMessage::SetFontAlpha(arg()?.parse()?)).await,
+            "speed" => self.send(DMLMessage::SetDMSpeed(arg()?.parse()?)).await,
+            "nick" => self.send(DMLMessage::ToggleShowNick).await,
+            "filter" => {
+                self.send(DMLMessage::SetFilter(
+                    args.iter().map(|it| it.to_string()).collect(),
+                ))
+                .await
+            }
+            "page" | "next" | "back" if !bili_video => Err(anyhow::anyhow!("not a video")),
+            "page" => {
+                self.cm.bvideo_info.borrow_mut().current_page = arg()?.parse()?;
+                self.send(DMLMessage::PlayVideo).await
+            }
+            "next" => {
+                self.cm.bvideo_info.borrow_mut().current_page += 1;
+                self.send(DMLMessage::PlayVideo).await
+            }
+            "back" => {
+                let p = self.cm.bvideo_info.borrow().current_page.saturating_sub(1);
+                self.cm.bvideo_info.borrow_mut().current_page = p.max(1);
+                self.send(DMLMessage::PlayVideo).await
+            }
+            "clip" => {
+                let secs = match args.first() {
+                    Some(it) => {
+                        crate::utils::parse_duration_secs(it).ok_or_else(|| anyhow::anyhow!("invalid duration"))?
+                    }
+                    None => 60,
+                };
+                self.send(DMLMessage::Clip(secs)).await
+            }
+            "quit" => self.send(DMLMessage::RequestExit).await,
+            _ => Err(anyhow::anyhow!("unknown command: {}", cmd)),
+        }
+    }
+}
+
+// control sockets of the running instances that still answer
+async fn find_sockets() -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    let mut rd = match tokio::fs::read_dir("/tmp").await {
+        Ok(it) => it,
+        Err(_) => return ret,
+    };
+    while let Ok(Some(entry)) = rd.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("dml-") && name.ends_with("-ctl") && UnixStream::connect(entry.path()).await.is_ok() {
+            ret.push(entry.path());
+        }
+    }
+    ret
+}
+
+/// `dmlive ctl`, sends one command to a running dmlive and prints what it answers.
+pub async fn run_ctl(socket: Option<String>, args: &[String]) -> Result<()> {
+    let socket = match socket {
+        Some(it) => PathBuf::from(it),
+        None => {
+            let mut found = find_sockets().await;
+            match found.len() {
+                0 => return Err(anyhow::anyhow!("no running dmlive found")),
+                1 => found.remove(0),
+                _ => {
+                    let list: Vec<String> = found.iter().map(|it| it.display().to_string()).collect();
+                    return Err(anyhow::anyhow!(
+                        "more than one dmlive running, pick one with --socket:\n{}",
+                        list.join("\n")
+                    ));
+                }
+            }
+        }
+    };
+    let (cmd, rest) = args.split_first().ok_or_else(|| dmlerr!())?;
+    let req = json!({"cmd": cmd, "args": rest});
+    let s = UnixStream::connect(&socket).await?;
+    let (r, mut w) = s.into_split();
+    w.write_all(format!("{}\n", req).as_bytes()).await?;
+    let line = BufReader::new(r).lines().next_line().await?.ok_or_else(|| dmlerr!())?;
+    let resp: Value = serde_json::from_str(&line)?;
+    if resp.pointer("/ok").and_then(|it| it.as_bool()) != Some(true) {
+        let e = resp.pointer("/error").and_then(|it| it.as_str()).unwrap_or("failed");
+        return Err(anyhow::anyhow!("{}", e));
+    }
+    if let Some(it) = resp.pointer("/result") {
+        println!("{}", serde_json::to_string_pretty(it)?);
+    }
+    Ok(())
+}
@@ -9,9 +9,7 @@ impl Bilibili {
         Bilibili {}
     }
 
-    pub async fn run(
-        &self, url: &str, dtx: async_channel::Sender<(String, String, String)>,
-    ) -> anyhow::Result<()> {
+    pub async fn run(&self, url: &str, dtx: async_channel::Sender<(String, String, String)>) -> anyhow::Result<()> {
         let client = reqwest::Client::builder()
             .deflate(false)
             .user_agent(crate::utils::gen_ua())
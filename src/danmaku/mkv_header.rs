@@ -1,7 +1,7 @@
+use crate::ipcmanager::DMLStream;
 use anyhow::*;
 use serde::Serialize;
 use tokio::io::AsyncWriteExt;
-use crate::ipcmanager::DMLStream;
 
 pub fn get_mkv_header() -> Vec<u8> {
     vec![
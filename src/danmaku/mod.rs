@@ -57,6 +57,9 @@ pub struct Danmaku {
     read_order: Cell<usize>,
     bili_video_cid: RefCell<String>,
     dchannels: RefCell<Vec<DanmakuChannel>>,
+    history: RefCell<VecDeque<(i64, String, String, String)>>, // unix time in ms, color, nick, text
+    events: tokio::sync::broadcast::Sender<String>,            // json, for the web overlay
+    blocked: RefCell<Vec<String>>,
     fk: fudujikiller::FudujiKiller,
 }
 
@@ -81,6 +84,9 @@ impl Danmaku {
             fk: fudujikiller::FudujiKiller::new(),
             bili_video_cid: RefCell::new("".into()),
             dchannels: RefCell::new(ch),
+            history: RefCell::new(VecDeque::new()),
+            events: tokio::sync::broadcast::channel(256).0,
+            blocked: RefCell::new(Vec::new()),
         }
     }
 
@@ -115,16 +121,119 @@ impl Danmaku {
         }
     }
 
+    pub async fn set_ratio_scale(&self, ratio_scale: f64) {
+        if ratio_scale > 0.0 {
+            self.ratio_scale.set(ratio_scale);
+        }
+    }
+
     pub async fn set_bili_video_cid(&self, cid: &str) {
         let mut bvc = self.bili_video_cid.borrow_mut();
         bvc.clear();
         bvc.push_str(cid);
     }
 
+    /// Danmaku containing any of `words` are dropped, no words lets everything through again.
+    pub fn set_filter(&self, words: Vec<String>) {
+        *self.blocked.borrow_mut() = words.into_iter().filter(|it| !it.is_empty()).collect();
+    }
+
+    fn dm_check(&self, d: &str) -> bool {
+        !self.blocked.borrow().iter().any(|it| d.contains(it.as_str())) && self.fk.dm_check(d)
+    }
+
     pub async fn toggle_show_nick(&self) {
         self.show_nick.set(self.show_nick.get().bitxor(true));
     }
 
+    fn remember(&self, c: &str, n: &str, d: &str) {
+        if self.cm.replay_buffer == 0 {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut h = self.history.borrow_mut();
+        while h.front().is_some_and(|it| now - it.0 > self.cm.replay_buffer as i64 * 1000) {
+            h.pop_front();
+        }
+        h.push_back((now, c.to_string(), n.to_string(), d.to_string()));
+    }
+
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<String> {
+        self.events.subscribe()
+    }
+
+    /// What the web overlay needs to draw danmaku like the subtitle track does.
+    pub fn overlay_config(&self) -> String {
+        serde_json::json!({
+            "type": "config",
+            "speed": self.cm.danmaku_speed.get(),
+            "font_scale": self.cm.font_scale.get(),
+            "alpha": self.cm.font_alpha.get(),
+            "show_nick": self.show_nick.get(),
+        })
+        .to_string()
+    }
+
+    // pts is the time of the danmaku on the subtitle track, the overlay waits for the video to get there
+    fn publish(&self, c: &str, n: &str, d: &str, pts: u64) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.events.send(
+            serde_json::json!({
+                "type": "danmaku",
+                "color": c,
+                "nick": n,
+                "text": d,
+                "pts": pts,
+            })
+            .to_string(),
+        );
+    }
+
+    /// Renders the danmaku shown since `start` (unix time in ms) as a standalone ass subtitle.
+    pub fn replay_ass(&self, start: i64) -> String {
+        let speed = self.cm.danmaku_speed.get() as i64;
+        let fs = self.font_size.get();
+        // the time each row is free again
+        let mut rows = vec![i64::MIN; self.channel_num.get()];
+        let mut ret = ASS_HEADER_TEXT.to_string();
+        for (t, c, n, d) in self.history.borrow().iter() {
+            let pts = t - start;
+            if pts < 0 {
+                continue;
+            }
+            let len = self.get_danmaku_display_length(n, d);
+            let row = match rows.iter().position(|it| *it <= pts) {
+                Some(it) => it,
+                None => continue,
+            };
+            rows[row] = pts + speed * len as i64 / (1920 + len as i64);
+            let t1 = NaiveTime::from_hms_opt(0, 0, 0).unwrap() + Duration::milliseconds(pts);
+            let t2 = t1 + Duration::milliseconds(speed);
+            let mut t1_s = t1.format("%k:%M:%S%.3f").to_string();
+            let mut t2_s = t2.format("%k:%M:%S%.3f").to_string();
+            t1_s.remove(t1_s.len() - 1);
+            t2_s.remove(t2_s.len() - 1);
+            ret.push_str(&format!(
+                r#"Dialogue: 0,{4},{5},Default,{8},0,0,0,,{{\alpha{0}\fs{7}\1c&{6}&\move(1920,{1},{2},{1})}}{9}{10}{3}"#,
+                format_args!("{:02x}", (self.cm.font_alpha.get() * 255_f64) as u8),
+                row * fs,
+                0 - len as isize,
+                &d,
+                t1_s,
+                t2_s,
+                format_args!("{}{}{}", &c[4..6], &c[2..4], &c[0..2]),
+                fs,
+                &n,
+                if self.show_nick.get() { n.as_str() } else { "" },
+                if self.show_nick.get() { ": " } else { "" },
+            ));
+            ret.push('\n');
+        }
+        ret
+    }
+
     fn get_avail_danmaku_channel(&self, c_pts: u64, len: usize) -> Option<usize> {
         let s = (1920.0 + len as f64) / self.cm.danmaku_speed.get() as f64;
         for (i, c) in self.dchannels.borrow_mut().iter_mut().enumerate() {
@@ -224,7 +333,11 @@ impl Danmaku {
     }
 
     pub async fn danmaku_client_task(&self, dtx: async_channel::Sender<(String, String, String)>) -> Result<()> {
+        let mut first = true;
         loop {
+            if !std::mem::take(&mut first) {
+                self.cm.metrics.ws_reconnects.inc();
+            }
             match match self.cm.site {
                 crate::config::Site::BiliLive => {
                     let b = bilibili::Bilibili::new();
@@ -285,8 +398,10 @@ impl Danmaku {
         let mut printed = false;
         'l1: loop {
             while let Ok(it) = rx.try_recv() {
+                self.cm.metrics.danmaku_received.inc();
                 dm_queue.push_back(it);
             }
+            self.cm.metrics.danmaku_queue.set(dm_queue.len() as u64);
             let mut launch = true;
             while launch {
                 let (co, ni, da) = dm_queue.get(0).ok_or_else(|| launch = false).unwrap_or(&empty_dm);
@@ -295,7 +410,8 @@ impl Danmaku {
                         println!("[{}] {}", &ni, &da);
                         printed = true;
                     }
-                    if !self.fk.dm_check(da) {
+                    if !self.dm_check(da) {
+                        self.cm.metrics.danmaku_dropped.inc();
                         let _ = dm_queue.pop_front();
                         continue;
                     }
@@ -304,6 +420,11 @@ impl Danmaku {
                 let c_pts = now.elapsed().as_millis() as u64;
                 match self.launch_single_danmaku(co, ni, &da, c_pts, &mut socket).await {
                     Ok(_) => {
+                        if !da.is_empty() {
+                            self.cm.metrics.danmaku_rendered.inc();
+                            self.remember(co, ni, &da);
+                            self.publish(co, ni, &da, c_pts);
+                        }
                         let _ = dm_queue.pop_front();
                         printed = false;
                     }
@@ -395,7 +516,9 @@ impl Danmaku {
 
     pub async fn run_bilivideo(&self, ratio_scale: f64) -> Result<()> {
         // FIXME: a little hack here to prevent danmaku on --quiet set to true
-        if self.cm.quiet { return Ok(()) }
+        if self.cm.quiet {
+            return Ok(());
+        }
 
         info!("ratio: {}", &ratio_scale);
         self.reset();
@@ -411,9 +534,31 @@ impl Danmaku {
         Ok(())
     }
 
+    pub async fn run_print(&self) -> Result<()> {
+        let (dtx, drx) = async_channel::unbounded::<(String, String, String)>();
+        let print_task = async {
+            while let Ok((_, ni, da)) = drx.recv().await {
+                self.cm.metrics.danmaku_received.inc();
+                if self.dm_check(&da) {
+                    println!("[{}] {}", &ni, &da);
+                } else {
+                    self.cm.metrics.danmaku_dropped.inc();
+                }
+            }
+        };
+        tokio::select! {
+            it = self.danmaku_client_task(dtx) => { it?; },
+            _ = print_task => {},
+        }
+        info!("danmaku printer exited");
+        Ok(())
+    }
+
     pub async fn run(&self, ratio_scale: f64, _start_pts: u64) -> Result<()> {
         // FIXME: a little hack here to prevent danmaku on --quiet set to true
-        if self.cm.quiet { return Ok(()) }
+        if self.cm.quiet {
+            return Ok(());
+        }
 
         self.reset();
         self.ratio_scale.set(ratio_scale);
@@ -1,20 +1,38 @@
 use crate::{
-    config::ConfigManager, danmaku::Danmaku, ffmpeg::FfmpegControl, ipcmanager::IPCManager, mpv::MpvControl,
-    streamer::Streamer, streamfinder::StreamFinder,
+    config::{ConfigManager, Quality},
+    control::ControlServer,
+    danmaku::Danmaku,
+    ffmpeg::FfmpegControl,
+    httpserver::HttpServer,
+    ipcmanager::IPCManager,
+    mpv::MpvControl,
+    streamer::Streamer,
+    streamfinder::StreamFinder,
 };
 use async_channel::{Receiver, Sender};
 use futures::StreamExt;
 use log::info;
-use std::rc::Rc;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 use tokio::time::Duration;
 
+const TITLE_CHECK_ITVL: u64 = 120; // in seconds
+
 #[allow(unused)]
 pub enum DMLMessage {
     SetFontScale(f64),
     SetFontAlpha(f64),
     SetDMSpeed(u64),
+    SetRewind(u64),
+    SetQuality(Quality),
+    CodecUnsupported(String),
+    Clip(u64),
+    SetFilter(Vec<String>),
     PlayVideo,
     SetVideoInfo((u64, u64, u64)),
+    VideoParamsChanged((u64, u64)),
     ToggleShowNick,
     FfmpegOutputReady,
     RequestRestart,
@@ -30,8 +48,13 @@ pub struct DMLive {
     sf: Rc<StreamFinder>,
     st: Rc<Streamer>,
     dm: Rc<Danmaku>,
+    hs: Rc<HttpServer>,
+    ctl: Rc<ControlServer>,
+    exit: tokio::sync::Notify,
     mrx: Receiver<DMLMessage>,
     mtx: Sender<DMLMessage>,
+    stream_info: RefCell<Option<(String, Vec<String>)>>,
+    reuse_stream: Cell<bool>,
 }
 
 impl DMLive {
@@ -42,6 +65,14 @@ impl DMLive {
         let sf = Rc::new(StreamFinder::new(cm.clone(), im.clone(), mtx.clone()));
         let st = Rc::new(Streamer::new(cm.clone(), im.clone(), mtx.clone()));
         let dm = Rc::new(Danmaku::new(cm.clone(), im.clone(), mtx.clone()));
+        let ctl = Rc::new(ControlServer::new(cm.clone(), im.clone(), mtx.clone()));
+        let hs = Rc::new(HttpServer::new(
+            dm.clone(),
+            ctl.clone(),
+            cm.clone(),
+            im.clone(),
+            mtx.clone(),
+        ));
         DMLive {
             ipc_manager: im,
             cm,
@@ -52,6 +83,11 @@ impl DMLive {
             sf,
             st,
             dm,
+            hs,
+            ctl,
+            exit: tokio::sync::Notify::new(),
+            stream_info: RefCell::new(None),
+            reuse_stream: Cell::new(false),
         }
     }
 
@@ -59,12 +95,27 @@ impl DMLive {
         let signal_task = async {
             let _ = tokio::signal::ctrl_c().await;
         };
+        // without a video track the danmaku have nowhere to go but the terminal
+        let print_danmaku = self.cm.audio_only && !self.cm.quiet && self.cm.site != crate::config::Site::BiliVideo;
+        let record = self.cm.run_mode.records();
+        let watch_title = record && self.cm.site != crate::config::Site::BiliVideo;
         tokio::select! {
             _ = self.dispatch_task() => {},
             _ = self.mc.run() => {},
             _ = self.play() => {},
+            _ = self.dm.run_print(), if print_danmaku => {},
+            _ = self.title_watch_task(), if watch_title => {},
+            _ = self.recover_task(), if record => {},
+            _ = self.control_task() => {},
+            _ = self.exit.notified() => {},
+            res = self.hs.run(), if self.cm.http_address.is_some() => {
+                let _ = res.map_err(|e| info!("http server error: {}", e));
+            },
+            _ = self.metrics_task(), if self.cm.metrics_address.is_some() => {},
             _ = signal_task => {},
         }
+        // the part being recorded when interrupted still gets its manifest and hook
+        self.fc.finish_record().await;
         match self.ipc_manager.stop().await {
             Ok(_) => {}
             Err(err) => info!("ipc manager stop error: {}", err),
@@ -101,11 +152,43 @@ impl DMLive {
             DMLMessage::ToggleShowNick => {
                 self.dm.toggle_show_nick().await;
             }
+            DMLMessage::SetRewind(secs) => {
+                if matches!(self.cm.site, crate::config::Site::YoutubeLive) {
+                    info!("rewind to {}s behind live", secs);
+                    self.cm.rewind.set(secs);
+                    // restart the streamer with the urls we already have
+                    self.reuse_stream.set(true);
+                    let _ = self.fc.quit().await;
+                }
+            }
+            DMLMessage::SetQuality(q) => {
+                info!("switch quality to {:?}", q);
+                self.cm.quality.set(q);
+                if matches!(self.cm.site, crate::config::Site::BiliVideo) {
+                    let _ = self.play_video().await.map_err(|e| info!("play video error: {}", e));
+                } else {
+                    // restart re-resolves the stream with the new quality
+                    let _ = self.fc.quit().await;
+                }
+            }
+            DMLMessage::CodecUnsupported(codec) => {
+                // ffmpeg is quitting, the next round resolves the stream again
+                if self.cm.exclude_codec(&codec) {
+                    info!("codec {} is not decodable, retry with the next one", &codec);
+                }
+            }
+            DMLMessage::Clip(secs) => {
+                let _ = self.clip(secs).await.map_err(|e| info!("clip error: {}", e));
+            }
             DMLMessage::RequestRestart => {
                 let _ = self.fc.quit().await;
             }
+            DMLMessage::SetFilter(words) => {
+                info!("danmaku filter: {:?}", &words);
+                self.dm.set_filter(words);
+            }
             DMLMessage::RequestExit => {
-                // self.quit().await;
+                self.exit.notify_one();
             }
             DMLMessage::SetVideoInfo((w, h, pts)) => {
                 info!("video info: w {} h {} pts {}", w, h, pts);
@@ -116,6 +199,11 @@ impl DMLive {
                     let _ = self.dm.run(16.0 * h as f64 / w as f64 / 9.0, pts).await;
                 }
             }
+            DMLMessage::VideoParamsChanged((w, h)) => {
+                info!("video params changed: w {} h {}", w, h);
+                self.dm.set_ratio_scale(16.0 * h as f64 / w as f64 / 9.0).await;
+                self.fc.request_split();
+            }
             DMLMessage::PlayVideo => {
                 let _ = self.play_video().await.map_err(|e| info!("play video error: {}", e));
             }
@@ -127,8 +215,18 @@ impl DMLive {
                         let _ = self.mc.reload_video().await;
                     }
                     crate::config::RunMode::Record => {
-                        if self.cm.http_address.is_none() {
-                            let _ = self.fc.write_record_task().await;
+                        let _ = self.fc.write_record_task().await;
+                    }
+                    crate::config::RunMode::Serve => {
+                        let _ = self.hs.feed_task().await.map_err(|e| info!("http feed error: {}", e));
+                    }
+                    crate::config::RunMode::PlayRecord => {
+                        // the relay listens before mpv is told to load it
+                        let _ = tokio::join!(self.fc.write_record_task(), self.mc.reload_video());
+                    }
+                    crate::config::RunMode::Restream => {
+                        if self.cm.burn_danmaku {
+                            let _ = self.mc.run_burn().await.map_err(|e| info!("burn error: {}", e));
                         }
                     }
                 }
@@ -136,13 +234,48 @@ impl DMLive {
         }
     }
 
+    // like the control socket, dmlive goes on without it
+    async fn metrics_task(&self) {
+        let _ = self.hs.run_metrics().await.map_err(|e| info!("metrics server error: {}", e));
+        futures::future::pending::<()>().await;
+    }
+
+    // without the control socket dmlive still plays
+    async fn control_task(&self) {
+        let _ = self.ctl.run().await.map_err(|e| info!("control server error: {}", e));
+        futures::future::pending::<()>().await;
+    }
+
+    // parts a killed dmlive left behind, done alongside the new recording
+    async fn recover_task(&self) {
+        self.fc.recover_parts().await;
+        futures::future::pending::<()>().await;
+    }
+
+    // a new title starts a new recording part
+    async fn title_watch_task(&self) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(TITLE_CHECK_ITVL)).await;
+            let title = match tokio::time::timeout(Duration::from_secs(30), self.sf.run()).await {
+                Ok(Ok((title, _))) => title,
+                _ => continue,
+            };
+            if title.is_empty() || self.cm.title.borrow().eq(&title) {
+                continue;
+            }
+            info!("title changed: {}", &title);
+            self.cm.set_title(&title);
+            self.fc.request_split();
+        }
+    }
+
     pub async fn play(&self) -> anyhow::Result<()> {
         loop {
             match self.cm.run_mode {
                 crate::config::RunMode::Play => {
                     if matches!(self.cm.site, crate::config::Site::BiliVideo) {
                         self.play_video().await?;
-                        tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
+                        std::future::pending::<()>().await;
                     } else {
                         self.play_live().await?;
                     }
@@ -153,6 +286,25 @@ impl DMLive {
                         return Err(anyhow::anyhow!("recording finished"));
                     }
                 }
+                crate::config::RunMode::Restream => {
+                    self.play_live().await?;
+                    if matches!(self.cm.site, crate::config::Site::BiliVideo) {
+                        return Err(anyhow::anyhow!("restream finished"));
+                    }
+                }
+                crate::config::RunMode::Serve => {
+                    self.play_live().await?;
+                    if matches!(self.cm.site, crate::config::Site::BiliVideo) {
+                        return Err(anyhow::anyhow!("serve finished"));
+                    }
+                }
+                crate::config::RunMode::PlayRecord => {
+                    self.play_live().await?;
+                    if matches!(self.cm.site, crate::config::Site::BiliVideo) {
+                        // the recording is done, mpv still plays what it has
+                        std::future::pending::<()>().await;
+                    }
+                }
             }
             tokio::time::sleep(Duration::from_millis(1000)).await;
         }
@@ -160,10 +312,18 @@ impl DMLive {
     }
 
     pub async fn play_live(&self) -> anyhow::Result<()> {
-        let (title, urls) = self.sf.run().await?;
+        let reused = if self.reuse_stream.replace(false) {
+            self.stream_info.borrow().clone()
+        } else {
+            None
+        };
+        let (title, urls) = match reused {
+            Some(it) => it,
+            None => self.sf.run().await?,
+        };
+        *self.stream_info.borrow_mut() = Some((title.clone(), urls.clone()));
         self.cm.set_stream_type(&urls[0]);
-        self.cm.title.borrow_mut().clear();
-        self.cm.title.borrow_mut().push_str(&title);
+        self.cm.set_title(&title);
         self.dm.set_bili_video_cid(&urls[0]).await;
         let ff_task = async {
             self.fc.run(&urls).await?;
@@ -174,19 +334,33 @@ impl DMLive {
             self.fc.quit().await?;
             anyhow::Ok(())
         };
-        if matches!(self.cm.site, crate::config::Site::BiliVideo) {
-            ff_task.await?;
+        self.cm.metrics.live.set(true);
+        let res = if matches!(self.cm.site, crate::config::Site::BiliVideo) {
+            ff_task.await
         } else {
             let (_ff_res, _st_res) = tokio::join!(ff_task, streamer_task);
+            Ok(())
+        };
+        self.cm.metrics.live.set(false);
+        res
+    }
+
+    pub async fn clip(&self, secs: u64) -> anyhow::Result<()> {
+        if !self.st.replay.enabled() {
+            let _ = self.mc.show_text("clips need replay_buffer in the config").await;
+            return Err(anyhow::anyhow!("replay buffer is off"));
         }
+        let start = self.st.replay.clip_start(secs).ok_or_else(|| anyhow::anyhow!("replay buffer is empty"))?;
+        let ass = self.dm.replay_ass(start);
+        let filename = self.st.replay.write_clip(start, &ass).await?;
+        let _ = self.mc.show_text(&format!("clip saved: {}", filename.display())).await;
         Ok(())
     }
 
     pub async fn play_video(&self) -> anyhow::Result<()> {
         let (title, urls) = self.sf.run().await?;
         self.cm.set_stream_type(&urls[0]);
-        self.cm.title.borrow_mut().clear();
-        self.cm.title.borrow_mut().push_str(&title);
+        self.cm.set_title(&title);
         self.dm.set_bili_video_cid(&urls[0]).await;
         self.mc.reload_edl_video(&urls).await?;
         Ok(())
@@ -0,0 +1,72 @@
+use super::mkvparser::MkvItem;
+use bytes::Bytes;
+use std::cell::RefCell;
+use tokio::sync::mpsc;
+
+pub const CLIENT_QUEUE_LEN: usize = 16; // in clusters or elements
+
+struct StreamClient {
+    tx: mpsc::Sender<Bytes>,
+    header_sent: bool,
+    // false while the client lags behind, it picks up again at the next cluster
+    synced: bool,
+}
+
+/// Hands the mkv stream of ffmpeg to readers that may come and go at any time.
+pub struct Fanout {
+    header: RefCell<Option<Bytes>>,
+    clients: RefCell<Vec<StreamClient>>,
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        Self {
+            header: RefCell::new(None),
+            clients: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(CLIENT_QUEUE_LEN);
+        let mut c = StreamClient {
+            tx,
+            header_sent: false,
+            synced: false,
+        };
+        // late joiners start with the header, then wait for a cluster
+        if let Some(it) = self.header.borrow().as_ref() {
+            c.header_sent = c.tx.try_send(it.clone()).is_ok();
+        }
+        self.clients.borrow_mut().push(c);
+        rx
+    }
+
+    pub fn broadcast(&self, item: &MkvItem) {
+        let mut clients = self.clients.borrow_mut();
+        match item {
+            MkvItem::Header(it) => {
+                for c in clients.iter_mut().filter(|it| !it.header_sent) {
+                    c.header_sent = c.tx.try_send(it.clone()).is_ok();
+                }
+                *self.header.borrow_mut() = Some(it.clone());
+            }
+            MkvItem::Cluster(it) => {
+                for c in clients.iter_mut().filter(|it| it.header_sent) {
+                    c.synced = c.tx.try_send(it.clone()).is_ok();
+                }
+            }
+            MkvItem::Data(it) => {
+                for c in clients.iter_mut().filter(|it| it.synced) {
+                    c.synced = c.tx.try_send(it.clone()).is_ok();
+                }
+            }
+        }
+        clients.retain(|it| !it.tx.is_closed());
+    }
+
+    /// Closes the readers, the next stream may not fit the header they already have.
+    pub fn clear(&self) {
+        self.clients.borrow_mut().clear();
+        self.header.borrow_mut().take();
+    }
+}
@@ -0,0 +1,199 @@
+use bytes::{Bytes, BytesMut};
+
+const ID_SEGMENT: u32 = 0x18538067;
+const ID_CLUSTER: u32 = 0x1f43b675;
+
+pub enum MkvItem {
+    // ebml header, segment header and everything before the first cluster
+    Header(Bytes),
+    // a cluster, or just its id and size if the size is unknown
+    Cluster(Bytes),
+    Data(Bytes),
+}
+
+// returns (value, length), the length marker is kept for ids and dropped for sizes
+fn read_vint(buf: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    if first == 0 {
+        return Some((u64::MAX, 0));
+    }
+    let len = first.leading_zeros() as usize + 1;
+    if buf.len() < len {
+        return None;
+    }
+    let mut v = if keep_marker {
+        first as u64
+    } else {
+        first as u64 & (0xff >> len)
+    };
+    for b in &buf[1..len] {
+        v = (v << 8) | *b as u64;
+    }
+    Some((v, len))
+}
+
+/// Splits a live matroska stream into elements, a new file can start at any cluster with the header in front.
+pub struct MkvParser {
+    buf: BytesMut,
+    header: BytesMut,
+    header_done: bool,
+}
+
+impl MkvParser {
+    pub fn new() -> Self {
+        MkvParser {
+            buf: BytesMut::with_capacity(64 * 1024),
+            header: BytesMut::new(),
+            header_done: false,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    pub fn next_item(&mut self) -> anyhow::Result<Option<MkvItem>> {
+        loop {
+            let (id, id_len) = match read_vint(&self.buf, true) {
+                Some(it) => it,
+                None => return Ok(None),
+            };
+            if id_len == 0 || id_len > 4 {
+                return Err(anyhow::anyhow!("invalid matroska element id"));
+            }
+            let (size, size_len) = match read_vint(&self.buf[id_len..], false) {
+                Some(it) => it,
+                None => return Ok(None),
+            };
+            if size_len == 0 {
+                return Err(anyhow::anyhow!("invalid matroska element size"));
+            }
+            let head_len = id_len + size_len;
+            let unknown_size = size == (1 << (7 * size_len)) - 1;
+            if id == ID_CLUSTER as u64 && !self.header_done {
+                self.header_done = true;
+                return Ok(Some(MkvItem::Header(self.header.split().freeze())));
+            }
+            // the segment is never closed on a live stream, its children follow
+            let len = if id == ID_SEGMENT as u64 || unknown_size {
+                head_len
+            } else {
+                head_len + size as usize
+            };
+            if self.buf.len() < len {
+                return Ok(None);
+            }
+            let data = self.buf.split_to(len);
+            if !self.header_done {
+                self.header.extend_from_slice(&data);
+                continue;
+            }
+            if id == ID_CLUSTER as u64 {
+                return Ok(Some(MkvItem::Cluster(data.freeze())));
+            }
+            return Ok(Some(MkvItem::Data(data.freeze())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut ret = id.to_vec();
+        ret.push(0x80 | data.len() as u8);
+        ret.extend_from_slice(data);
+        ret
+    }
+
+    // what ffmpeg writes to a pipe: an open segment, a sized cluster, then one of unknown size
+    fn fixture() -> (Vec<u8>, usize) {
+        let mut data = element(
+            &[0x1a, 0x45, 0xdf, 0xa3],
+            &element(&[0x42, 0x82], b"matroska"),
+        );
+        data.extend([0x18, 0x53, 0x80, 0x67, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        data.extend(element(
+            &[0x15, 0x49, 0xa9, 0x66],
+            &element(&[0x2a, 0xd7, 0xb1], &[0x0f, 0x42, 0x40]),
+        ));
+        data.extend(element(
+            &[0x16, 0x54, 0xae, 0x6b],
+            &element(&[0xae], &element(&[0xd7], &[1])),
+        ));
+        let header_len = data.len();
+        let mut cluster = element(&[0xe7], &[0]);
+        cluster.extend(element(&[0xa3], &[0x81, 0, 0, 0x80, 0xaa]));
+        data.extend(element(&[0x1f, 0x43, 0xb6, 0x75], &cluster));
+        data.extend([0x1f, 0x43, 0xb6, 0x75, 0xff]);
+        data.extend(element(&[0xe7], &[40]));
+        data.extend(element(&[0xa3], &[0x81, 0, 0x28, 0x80, 0xbb]));
+        (data, header_len)
+    }
+
+    fn parse(chunks: impl Iterator<Item = Vec<u8>>) -> Vec<MkvItem> {
+        let mut parser = MkvParser::new();
+        let mut ret = Vec::new();
+        for it in chunks {
+            parser.push(&it);
+            while let Some(it) = parser.next_item().unwrap() {
+                ret.push(it);
+            }
+        }
+        ret
+    }
+
+    fn kinds(items: &[MkvItem]) -> Vec<(&'static str, usize)> {
+        items
+            .iter()
+            .map(|it| match it {
+                MkvItem::Header(it) => ("header", it.len()),
+                MkvItem::Cluster(it) => ("cluster", it.len()),
+                MkvItem::Data(it) => ("data", it.len()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_clusters() {
+        let (data, header_len) = fixture();
+        let items = parse(std::iter::once(data.clone()));
+        assert_eq!(
+            kinds(&items),
+            [
+                ("header", header_len),
+                ("cluster", 15),
+                ("cluster", 5),
+                ("data", 3),
+                ("data", 7)
+            ]
+        );
+        // nothing is lost or reordered, a part is the header and the clusters after it
+        let out: Vec<u8> = items
+            .iter()
+            .flat_map(|it| match it {
+                MkvItem::Header(it) | MkvItem::Cluster(it) | MkvItem::Data(it) => it.to_vec(),
+            })
+            .collect();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn waits_for_whole_elements() {
+        let (data, _) = fixture();
+        let whole = parse(std::iter::once(data.clone()));
+        let split = parse(data.iter().map(|it| vec![*it]));
+        assert_eq!(kinds(&whole), kinds(&split));
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        let mut parser = MkvParser::new();
+        parser.push(&[0x00, 0x81, 0x00]);
+        assert!(parser.next_item().is_err());
+        let mut parser = MkvParser::new();
+        parser.push(&[0x08, 0x00, 0x00, 0x00, 0x00, 0x81, 0x00]);
+        assert!(parser.next_item().is_err());
+    }
+}
@@ -1,22 +1,49 @@
+pub mod fanout;
+pub mod mkvparser;
+pub mod record;
+
 use crate::config::{RunMode, Site, StreamType};
 use crate::ipcmanager::IPCManager;
 use crate::{config::ConfigManager, dmlive::DMLMessage};
 use anyhow::anyhow;
 use anyhow::Result;
-use log::info;
-use std::cell::RefCell;
+use fanout::Fanout;
+use futures::StreamExt;
+use log::{info, warn};
+use mkvparser::{MkvItem, MkvParser};
+use record::{RecordEvent, RecordPart, Recorder};
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use tokio::io::{AsyncRead, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::process::ChildStdin;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt},
     process::Command,
 };
 
+/// Connects to an ffmpeg output listening on `addr`.
+pub async fn connect_output(addr: &str) -> Result<TcpStream> {
+    // ffmpeg listens only after the outputs before it are connected
+    let mut retry = 0;
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(it) => return Ok(it),
+            Err(_) if retry < 50 => retry += 1,
+            Err(e) => return Err(e.into()),
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    }
+}
+
 pub struct FfmpegControl {
     ipc_manager: Rc<IPCManager>,
     cm: Rc<ConfigManager>,
     ff_stdin: RefCell<Option<ChildStdin>>,
+    split_requested: Cell<bool>,
+    record_closers: RefCell<Vec<tokio::task::JoinHandle<()>>>,
+    cover: RefCell<Option<(String, String)>>, // url and mime type of the downloaded cover
+    started: Cell<bool>,
     mtx: async_channel::Sender<DMLMessage>,
 }
 impl FfmpegControl {
@@ -26,35 +53,225 @@ impl FfmpegControl {
             cm,
             mtx,
             ff_stdin: RefCell::new(None),
+            split_requested: Cell::new(false),
+            record_closers: RefCell::new(Vec::new()),
+            cover: RefCell::new(None),
+            started: Cell::new(false),
+        }
+    }
+
+    /// The recording moves on to a new part at the next cluster.
+    pub fn request_split(&self) {
+        if self.cm.run_mode.records() {
+            self.split_requested.set(true);
+        }
+    }
+
+    // downloads the cover once for every url it changes to, returns its mime type
+    async fn fetch_cover(&self) -> Option<String> {
+        let url = self.cm.cover_url.borrow().clone();
+        if url.is_empty() {
+            return None;
+        }
+        if let Some((u, mime)) = self.cover.borrow().as_ref() {
+            if u.eq(&url) {
+                return Some(mime.clone());
+            }
+        }
+        let res = async {
+            let resp = reqwest::Client::new()
+                .get(&url)
+                .header("User-Agent", crate::utils::gen_ua())
+                .timeout(std::time::Duration::from_secs(10))
+                .send()
+                .await?
+                .error_for_status()?;
+            let mime = resp
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|it| it.to_str().ok())
+                .and_then(|it| it.split(';').next())
+                .filter(|it| it.starts_with("image/"))
+                .unwrap_or("image/jpeg")
+                .to_string();
+            let data = resp.bytes().await?;
+            tokio::fs::write(self.ipc_manager.get_cover_path(), &data).await?;
+            anyhow::Ok(mime)
+        }
+        .await;
+        match res {
+            Ok(mime) => {
+                *self.cover.borrow_mut() = Some((url, mime.clone()));
+                Some(mime)
+            }
+            Err(e) => {
+                warn!("record: fetch cover error: {}", e);
+                None
+            }
+        }
+    }
+
+    fn spawn_part(
+        &self, rec: &mut Recorder, filename: std::path::PathBuf, cover: Option<String>,
+    ) -> Result<ChildStdin> {
+        info!("record part: {}", filename.display());
+        if let Some(it) = filename.parent() {
+            std::fs::create_dir_all(it)?;
+        }
+        let history = self
+            .cm
+            .title_history
+            .borrow()
+            .iter()
+            .map(|(time, title)| format!("{} {}", time.to_rfc3339(), title))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-y", "-hide_banner"]);
+        cmd.args(["-i", "-"]);
+        cmd.args(["-c", "copy"]);
+        cmd.arg("-metadata").arg(format!("title={}", self.cm.title.borrow()));
+        cmd.arg("-metadata").arg(format!("ARTIST={}", self.cm.streamer.borrow()));
+        cmd.arg("-metadata").arg(format!("SITE={}", self.cm.site.name()));
+        cmd.arg("-metadata").arg(format!("URL={}", self.cm.room_url));
+        let date = chrono::Local::now().to_rfc3339();
+        cmd.arg("-metadata").arg(format!("DATE_RECORDED={}", date));
+        cmd.arg("-metadata").arg(format!("TITLE_HISTORY={}", history));
+        if let Some(mime) = cover {
+            let ext = match mime.as_str() {
+                "image/jpeg" => "jpg",
+                it => it.trim_start_matches("image/"),
+            };
+            cmd.arg("-attach").arg(self.ipc_manager.get_cover_path());
+            cmd.arg("-metadata:s:t").arg(format!("mimetype={}", mime));
+            cmd.arg("-metadata:s:t").arg(format!("filename=cover.{}", ext));
+        }
+        cmd.args(["-f", "matroska"]);
+        cmd.arg(record::part_path(&filename));
+        let mut ff = cmd.stdin(std::process::Stdio::piped()).kill_on_drop(false).spawn()?;
+        let stdin = ff.stdin.take().ok_or_else(|| anyhow!("ffmpeg stdin not found"))?;
+        let part = RecordPart {
+            path: filename,
+            part: rec.part_no(),
+            title: self.cm.title.borrow().clone(),
+            start: chrono::Local::now(),
+        };
+        if let Some(it) = rec.start_part(part, ff) {
+            let mut closers = self.record_closers.borrow_mut();
+            closers.retain(|it| !it.is_finished());
+            closers.push(it);
+        }
+        Ok(stdin)
+    }
+
+    pub async fn recover_parts(&self) {
+        record::recover(&self.cm.output_dir).await;
+    }
+
+    /// Waits for the parts still being finalized and their hooks.
+    pub async fn finish_record(&self) {
+        let closers = self.record_closers.take();
+        for it in closers {
+            let _ = it.await;
         }
     }
 
     pub async fn write_record_task(&self) -> Result<()> {
-        let in_stream = self.ipc_manager.get_f2m_socket_path();
-        let max_len = match self.cm.title.borrow().char_indices().nth(70) {
-            Some(it) => it.0,
-            None => self.cm.title.borrow().len(),
+        let addr = match self.cm.run_mode {
+            RunMode::PlayRecord => self.ipc_manager.get_rec_socket_addr(),
+            _ => self.ipc_manager.get_f2m_socket_addr(),
         };
-        let now = chrono::Local::now();
-        let filename = format!(
-            "{} - {}.mkv",
-            self.cm.title.borrow()[..max_len].replace('/', "-"),
-            now.format("%F %T")
-        );
-        let mut cmd = Command::new("ffmpeg");
-        cmd.args(["-y", "-hide_banner", "-nostdin"]);
-        cmd.arg("-i");
-        cmd.arg(&in_stream);
-        cmd.args(["-c", "copy", "-f", "matroska"]);
-        cmd.arg(&filename);
-        let mut ff = cmd
-            .stdin(std::process::Stdio::null())
-            // .stderr(std::process::Stdio::null())
-            .kill_on_drop(false)
-            .spawn()
-            .unwrap();
-        let _ = ff.wait().await;
-        Ok(())
+        // mpv plays from here, it can connect again whenever it reloads
+        let relay = match self.cm.run_mode {
+            RunMode::PlayRecord => Some(TcpListener::bind(self.ipc_manager.get_f2m_socket_addr()).await?),
+            _ => None,
+        };
+        let fanout = Fanout::new();
+        let mut in_stream = connect_output(&addr).await?;
+        let mut rec = Recorder::new(&self.cm);
+        let mut parser = MkvParser::new();
+        let mut header = bytes::Bytes::new();
+        let mut part: Option<ChildStdin> = None;
+        let mut part_start = std::time::Instant::now();
+        let mut part_size = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        let res = async {
+            loop {
+                let n = in_stream.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                parser.push(&buf[..n]);
+                while let Some(item) = parser.next_item()? {
+                    fanout.broadcast(&item);
+                    let data = match item {
+                        MkvItem::Header(it) => {
+                            header = it;
+                            continue;
+                        }
+                        MkvItem::Cluster(it) => {
+                            let split = part.is_none()
+                                || self.split_requested.get()
+                                || (self.cm.split_every > 0 && part_start.elapsed().as_secs() >= self.cm.split_every)
+                                || (self.cm.split_size > 0 && part_size >= self.cm.split_size);
+                            if split {
+                                self.split_requested.set(false);
+                                let filename = rec.next_filename(&self.cm);
+                                if let Some(it) = part.take() {
+                                    // ffmpeg writes the index and quits, the part is finalized in the background
+                                    drop(it);
+                                    rec.close_part(RecordEvent::Split, Some(&filename));
+                                }
+                                let cover = self.fetch_cover().await;
+                                let mut stdin = self.spawn_part(&mut rec, filename, cover)?;
+                                stdin.write_all(&header).await?;
+                                part = Some(stdin);
+                                part_start = std::time::Instant::now();
+                                part_size = header.len() as u64;
+                            }
+                            it
+                        }
+                        MkvItem::Data(it) => it,
+                    };
+                    if let Some(stdin) = part.as_mut() {
+                        stdin.write_all(&data).await?;
+                        part_size += data.len() as u64;
+                    }
+                }
+            }
+            anyhow::Ok(())
+        };
+        let relay_task = async {
+            let listener = match relay.as_ref() {
+                Some(it) => it,
+                None => return futures::future::pending::<Result<()>>().await,
+            };
+            let mut tasks = futures::stream::FuturesUnordered::new();
+            loop {
+                tokio::select! {
+                    Some(_) = tasks.next() => {},
+                    it = listener.accept() => {
+                        let (mut s, _) = it?;
+                        let mut rx = fanout.subscribe();
+                        tasks.push(async move {
+                            while let Some(data) = rx.recv().await {
+                                if s.write_all(&data).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+        };
+        let res = tokio::select! {
+            it = res => it,
+            it = relay_task => it,
+        };
+        fanout.clear();
+        drop(part);
+        rec.close_part(RecordEvent::Finish, None);
+        res
     }
 
     pub fn create_pre_ff_command(&self) -> Result<Command> {
@@ -80,6 +297,20 @@ impl FfmpegControl {
         // ret.arg("-loglevel").arg("quiet");
         // ret.args(["-probesize", "102400"]);
         match self.cm.stream_type.get() {
+            crate::config::StreamType::DASH if self.cm.audio_only => {
+                if self.cm.site == Site::BiliVideo {
+                    ret.args([
+                        "-user_agent",
+                        &crate::utils::gen_ua(),
+                        "-headers",
+                        "Referer: https://www.bilibili.com/",
+                    ]);
+                    ret.arg("-i").arg(&rurl[2]);
+                } else {
+                    ret.arg("-i").arg(self.ipc_manager.get_audio_socket_path());
+                }
+                ret.args(["-map", "0:a:0"]);
+            }
             crate::config::StreamType::DASH => {
                 if self.cm.site == Site::BiliVideo {
                     ret.args(&[
@@ -103,6 +334,14 @@ impl FfmpegControl {
                 ret.arg("-i").arg(self.ipc_manager.get_danmaku_socket_path());
                 ret.args(["-map", "0:v:0?", "-map", "1:a:0?", "-map", "2:s:0"]);
             }
+            _ if self.cm.audio_only => {
+                if self.cm.stream_type.get() == StreamType::HLS(0) {
+                    ret.arg("-i").arg("-");
+                } else {
+                    ret.arg("-i").arg(self.ipc_manager.get_video_socket_path());
+                }
+                ret.args(["-map", "0:a:0"]);
+            }
             crate::config::StreamType::HLS(0) => {
                 ret.arg("-i").arg("-");
                 ret.arg("-i").arg(self.ipc_manager.get_danmaku_socket_path());
@@ -115,25 +354,37 @@ impl FfmpegControl {
             }
         }
         ret.args(&["-c", "copy"]);
-        ret.args(&[
-            "-metadata",
-            format!("title={}", self.cm.title.borrow()).as_str(),
-            "-f",
-            "matroska",
-        ]);
+        ret.args(&["-metadata", format!("title={}", self.cm.title.borrow()).as_str()]);
         match self.cm.run_mode {
             RunMode::Play => {
+                ret.args(["-f", "matroska"]);
                 ret.arg("-listen").arg("1").arg(self.ipc_manager.get_f2m_socket_path());
             }
-            RunMode::Record => {
-                match self.cm.http_address.as_ref() {
-                    Some(it) => {
-                        ret.arg("-listen").arg("1").arg(it);
-                    }
-                    None => {
-                        ret.arg("-listen").arg("1").arg(self.ipc_manager.get_f2m_socket_path());
-                    }
-                };
+            RunMode::PlayRecord => {
+                // the recorder relays it to mpv
+                ret.args(["-f", "matroska"]);
+                ret.arg("-listen").arg("1").arg(self.ipc_manager.get_rec_socket_path());
+            }
+            RunMode::Restream if self.cm.burn_danmaku => {
+                // mpv renders the danmaku into the picture and pushes it
+                ret.args(["-f", "matroska"]);
+                ret.arg("-listen").arg("1").arg(self.ipc_manager.get_f2m_socket_path());
+            }
+            RunMode::Restream => {
+                let output = self.cm.output.as_deref().unwrap_or("");
+                // flv and mpeg-ts can not carry the ass track
+                if !output.ends_with(".mkv") {
+                    ret.arg("-sn");
+                }
+                if let Some(it) = self.cm.output_format() {
+                    ret.args(["-f", it]);
+                }
+                ret.arg(output);
+            }
+            RunMode::Record | RunMode::Serve => {
+                // read by the recorder or the http server
+                ret.args(["-f", "matroska"]);
+                ret.arg("-listen").arg("1").arg(self.ipc_manager.get_f2m_socket_path());
             }
         }
         Ok(ret)
@@ -155,13 +406,30 @@ impl FfmpegControl {
         let mut reader = BufReader::new(ffstderr).lines();
         let res_re = regex::Regex::new(r"Stream #[0-9].+? Video:.*?\D(\d{3,5})x(\d{2,5})\D.*").unwrap();
         let pts_re = regex::Regex::new(r"Duration: ([^,\s]+),\s+(start: ([0-9.]+))*.+").unwrap();
-        let dm_re = regex::Regex::new(r"Stream #[0-9:]+\s*Subtitle:\s*ass").unwrap();
+        let dm_re = if self.cm.audio_only {
+            // there is no danmaku track to wait for
+            regex::Regex::new(r"Stream #[0-9:]+.*?\s*Audio:").unwrap()
+        } else {
+            regex::Regex::new(r"Stream #[0-9:]+\s*Subtitle:\s*ass").unwrap()
+        };
+        let vcodec_re = regex::Regex::new(r"Video:\s*(\w+)").unwrap();
+        let unsupported_re =
+            regex::Regex::new(r"Could not find codec parameters|Unsupported codec|Video:\s*none").unwrap();
         let mut vinfo_sent = false;
         let mut ffready_sent = false;
+        let mut vcodec = String::new();
         while let Some(line) = reader.next_line().await.unwrap_or(None) {
             info!("{}", &line);
             let line = line.trim();
-            if let Some(_it) = pts_re.captures(&line) {
+            if let Some(it) = vcodec_re.captures(line) {
+                if vcodec.is_empty() {
+                    vcodec.push_str(&it[1]);
+                }
+            }
+            if unsupported_re.is_match(line) {
+                let _ = self.mtx.send(DMLMessage::CodecUnsupported(vcodec.clone())).await;
+                let _ = self.quit().await;
+            } else if let Some(_it) = pts_re.captures(&line) {
                 // duration = utils::str_to_ms(&it[1]);
                 // let st: f64 = it.get(3).map_or("0", |it| it.as_str()).parse().unwrap_or(0.0);
                 // start = (st * 1000.0) as u64;
@@ -189,6 +457,9 @@ impl FfmpegControl {
     }
 
     pub async fn run(&self, rurl: &Vec<String>) -> Result<()> {
+        if self.started.replace(true) {
+            self.cm.metrics.ffmpeg_restarts.inc();
+        }
         let mut ff = self
             .create_ff_command(rurl)?
             .stdin(std::process::Stdio::piped())
@@ -0,0 +1,381 @@
+use crate::config::ConfigManager;
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tokio::{
+    process::{Child, Command},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+const ORPHAN_IDLE: u64 = 60; // in seconds
+
+#[derive(Clone, Copy)]
+pub enum RecordEvent {
+    Start,
+    Split,
+    Finish,
+}
+
+impl RecordEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordEvent::Start => "start",
+            RecordEvent::Split => "split",
+            RecordEvent::Finish => "finish",
+        }
+    }
+}
+
+pub struct RecordPart {
+    pub path: PathBuf,
+    pub part: u32,
+    pub title: String,
+    pub start: DateTime<Local>,
+}
+
+/// What the manifest and the hooks tell about the room, taken when a session starts.
+#[derive(Clone)]
+pub struct RoomInfo {
+    site: &'static str,
+    room_url: String,
+    room_id: String,
+    streamer: String,
+    quality: String,
+    on_start: Option<String>,
+    on_split: Option<String>,
+    on_finish: Option<String>,
+}
+
+impl RoomInfo {
+    pub fn new(cm: &ConfigManager) -> Self {
+        RoomInfo {
+            site: cm.site.name(),
+            room_url: cm.room_url.clone(),
+            room_id: cm.room_id(),
+            streamer: cm.streamer.borrow().clone(),
+            quality: cm.quality.get().label().to_string(),
+            on_start: cm.on_record_start.clone(),
+            on_split: cm.on_record_split.clone(),
+            on_finish: cm.on_record_finish.clone(),
+        }
+    }
+}
+
+/// The parts written from one connection to ffmpeg, described by a json manifest next to the first one.
+pub struct RecordSession {
+    pub manifest: PathBuf,
+    pub start: DateTime<Local>,
+    pub parts: Vec<serde_json::Value>,
+}
+
+impl RecordSession {
+    pub fn new(first: &Path) -> Self {
+        // parts end in ".mkv", so the title is left alone
+        RecordSession {
+            manifest: first.with_extension("json"),
+            start: Local::now(),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn add_part(&mut self, part: &RecordPart, end: &DateTime<Local>, danmaku: Option<&Path>) {
+        let size = std::fs::metadata(&part.path).map(|it| it.len()).unwrap_or(0);
+        self.parts.push(json!({
+            "file": part.path.file_name().map(|it| it.to_string_lossy()),
+            "danmaku": danmaku.and_then(|it| it.file_name()).map(|it| it.to_string_lossy()),
+            "part": part.part,
+            "title": part.title,
+            "start_time": part.start.to_rfc3339(),
+            "end_time": end.to_rfc3339(),
+            "duration": (*end - part.start).num_seconds(),
+            "size": size,
+        }));
+    }
+
+    pub async fn write_manifest(&self, info: &RoomInfo, finished: bool) {
+        let end = self.parts.last().and_then(|it| it["end_time"].as_str()).unwrap_or("");
+        let j = json!({
+            "site": info.site,
+            "room_url": info.room_url,
+            "room_id": info.room_id,
+            "streamer": info.streamer,
+            "quality": info.quality,
+            "start_time": self.start.to_rfc3339(),
+            "end_time": end,
+            "finished": finished,
+            "parts": self.parts,
+        });
+        let data = serde_json::to_vec_pretty(&j).unwrap_or_default();
+        if let Err(e) = tokio::fs::write(&self.manifest, data).await {
+            warn!("record: write manifest error: {}", e);
+        }
+    }
+}
+
+/// Runs the hook configured for `event` with `sh -c`, without waiting for it.
+pub fn run_hook(
+    info: &RoomInfo, event: RecordEvent, part: &RecordPart, manifest: &Path, next: Option<&Path>,
+    danmaku: Option<&Path>,
+) {
+    let cmd = match event {
+        RecordEvent::Start => info.on_start.as_ref(),
+        RecordEvent::Split => info.on_split.as_ref(),
+        RecordEvent::Finish => info.on_finish.as_ref(),
+    };
+    let cmd = match cmd {
+        Some(it) if !it.trim().is_empty() => it,
+        _ => return,
+    };
+    let duration = match event {
+        RecordEvent::Start => 0,
+        _ => (Local::now() - part.start).num_seconds(),
+    };
+    info!("record: run {} hook", event.name());
+    let mut c = Command::new("sh");
+    c.arg("-c").arg(cmd);
+    c.env("DML_EVENT", event.name());
+    c.env("DML_FILE", &part.path);
+    c.env("DML_DANMAKU_FILE", danmaku.unwrap_or(Path::new("")));
+    c.env("DML_NEXT_FILE", next.unwrap_or(Path::new("")));
+    c.env("DML_MANIFEST", manifest);
+    c.env("DML_PART", part.part.to_string());
+    c.env("DML_SITE", info.site);
+    c.env("DML_ROOM", &info.room_url);
+    c.env("DML_ROOM_ID", &info.room_id);
+    c.env("DML_STREAMER", &info.streamer);
+    c.env("DML_TITLE", &part.title);
+    c.env("DML_DURATION", duration.to_string());
+    match c.stdin(std::process::Stdio::null()).kill_on_drop(false).spawn() {
+        Ok(mut it) => {
+            tokio::spawn(async move {
+                let _ = it.wait().await;
+            });
+        }
+        Err(e) => warn!("record: {} hook error: {}", event.name(), e),
+    }
+}
+
+struct CloseJob {
+    part: RecordPart,
+    ff: Child,
+    end: DateTime<Local>,
+    event: RecordEvent,
+    next: Option<PathBuf>,
+}
+
+// finalizes the parts of a session one after another, so the live stream never waits for a remux
+fn spawn_closer(info: RoomInfo, mut session: RecordSession) -> (mpsc::UnboundedSender<CloseJob>, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<CloseJob>();
+    let handle = tokio::spawn(async move {
+        while let Some(mut job) = rx.recv().await {
+            let _ = job.ff.wait().await;
+            let danmaku = match finalize(&job.part.path).await {
+                Ok(_) => extract_danmaku(&job.part.path).await,
+                Err(e) => {
+                    warn!("record: finalize {} error: {}", job.part.path.display(), e);
+                    None
+                }
+            };
+            let finished = matches!(job.event, RecordEvent::Finish);
+            session.add_part(&job.part, &job.end, danmaku.as_deref());
+            session.write_manifest(&info, finished).await;
+            run_hook(
+                &info,
+                job.event,
+                &job.part,
+                &session.manifest,
+                job.next.as_deref(),
+                danmaku.as_deref(),
+            );
+            if finished {
+                break;
+            }
+        }
+    });
+    (tx, handle)
+}
+
+/// The parts written from one connection to ffmpeg. The part being written is finished when it is dropped.
+pub struct Recorder {
+    info: RoomInfo,
+    time: DateTime<Local>, // parts of one session share the time they started with
+    part_no: u32,
+    current: Option<(RecordPart, Child)>,
+    closer: Option<mpsc::UnboundedSender<CloseJob>>,
+}
+
+impl Recorder {
+    pub fn new(cm: &ConfigManager) -> Self {
+        Recorder {
+            info: RoomInfo::new(cm),
+            time: Local::now(),
+            part_no: 0,
+            current: None,
+            closer: None,
+        }
+    }
+
+    pub fn next_filename(&mut self, cm: &ConfigManager) -> PathBuf {
+        self.part_no += 1;
+        cm.record_filename(&self.time, &format!("{:03}", self.part_no))
+    }
+
+    pub fn part_no(&self) -> u32 {
+        self.part_no
+    }
+
+    /// Takes over the ffmpeg writing `part`. The first part starts the session, its closer is returned.
+    pub fn start_part(&mut self, part: RecordPart, ff: Child) -> Option<JoinHandle<()>> {
+        let mut handle = None;
+        if self.closer.is_none() {
+            let session = RecordSession::new(&part.path);
+            run_hook(
+                &self.info,
+                RecordEvent::Start,
+                &part,
+                &session.manifest,
+                None,
+                None,
+            );
+            let (tx, it) = spawn_closer(self.info.clone(), session);
+            self.closer = Some(tx);
+            handle = Some(it);
+        }
+        self.current = Some((part, ff));
+        handle
+    }
+
+    /// Hands the current part to the closer, its ffmpeg quits once stdin is closed.
+    pub fn close_part(&mut self, event: RecordEvent, next: Option<&Path>) {
+        if let Some((part, ff)) = self.current.take() {
+            if let Some(tx) = self.closer.as_ref() {
+                let _ = tx.send(CloseJob {
+                    part,
+                    ff,
+                    end: Local::now(),
+                    event,
+                    next: next.map(|it| it.to_path_buf()),
+                });
+            }
+        }
+        if matches!(event, RecordEvent::Finish) {
+            self.closer.take();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.close_part(RecordEvent::Finish, None);
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+/// Where a part is written to until it is finalized.
+pub fn part_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".part")
+}
+
+/// Remuxes the `.part` file of `path` so it gets cues and a duration, then renames it to `path`.
+pub async fn finalize(path: &Path) -> anyhow::Result<()> {
+    let part = part_path(path);
+    let tmp = with_suffix(path, ".remux");
+    let status = Command::new("ffmpeg")
+        .args(["-y", "-hide_banner", "-nostdin", "-loglevel", "error"])
+        .arg("-i")
+        .arg(&part)
+        .args(["-map", "0", "-c", "copy", "-f", "matroska"])
+        .arg(&tmp)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await?;
+    if !status.success() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(anyhow::anyhow!("ffmpeg exited with {}", status));
+    }
+    tokio::fs::rename(&tmp, path).await?;
+    tokio::fs::remove_file(&part).await?;
+    info!("record: finalized {}", path.display());
+    Ok(())
+}
+
+/// Copies the danmaku track of a finished part to an `.ass` next to it, so hooks do not have to demux the mkv.
+/// Audio only parts have no such track.
+pub async fn extract_danmaku(path: &Path) -> Option<PathBuf> {
+    let ass = path.with_extension("ass");
+    let status = Command::new("ffmpeg")
+        .args(["-y", "-hide_banner", "-nostdin", "-loglevel", "error"])
+        .arg("-i")
+        .arg(path)
+        .args(["-map", "0:s:0", "-c", "copy", "-f", "ass"])
+        .arg(&ass)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await;
+    match status {
+        Ok(it) if it.success() => Some(ass),
+        it => {
+            info!(
+                "record: no danmaku extracted from {}: {:?}",
+                path.display(),
+                it
+            );
+            let _ = tokio::fs::remove_file(&ass).await;
+            None
+        }
+    }
+}
+
+/// Finalizes `.part` files left behind by a killed dmlive, the `.part` is kept if that fails.
+pub async fn recover(dir: &Path) {
+    let mut dirs = vec![(dir.to_path_buf(), 0)];
+    while let Some((dir, depth)) = dirs.pop() {
+        let mut rd = match tokio::fs::read_dir(&dir).await {
+            Ok(it) => it,
+            Err(_) => continue,
+        };
+        while let Ok(Some(entry)) = rd.next_entry().await {
+            let path = entry.path();
+            let meta = match entry.metadata().await {
+                Ok(it) => it,
+                Err(_) => continue,
+            };
+            if meta.is_dir() {
+                // templates seldom go deeper than site/streamer/date
+                if depth < 3 {
+                    dirs.push((path, depth + 1));
+                }
+                continue;
+            }
+            // another dmlive may still be writing it
+            let idle = meta.modified().ok().and_then(|it| it.elapsed().ok()).unwrap_or_default();
+            if idle < std::time::Duration::from_secs(ORPHAN_IDLE) {
+                continue;
+            }
+            let name = path.to_string_lossy();
+            if let Some(it) = name.strip_suffix(".mkv.remux") {
+                let _ = tokio::fs::remove_file(&path).await;
+                info!("record: removed unfinished remux of {}.mkv", it);
+            } else if let Some(it) = name.strip_suffix(".part").filter(|it| it.ends_with(".mkv")) {
+                let dest = PathBuf::from(it);
+                if dest.exists() {
+                    warn!("record: {} exists, keep {}", dest.display(), path.display());
+                    continue;
+                }
+                info!("record: recover {}", path.display());
+                if let Err(e) = finalize(&dest).await {
+                    warn!("record: recover {} error: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+pub mod request;
+
+use crate::{
+    config::ConfigManager,
+    control::ControlServer,
+    danmaku::Danmaku,
+    dmlerr,
+    dmlive::DMLMessage,
+    ffmpeg::{fanout::Fanout, mkvparser::MkvParser},
+    ipcmanager::IPCManager,
+};
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use log::info;
+use request::Request;
+use std::{path::PathBuf, rc::Rc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::broadcast::error::RecvError,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+const INDEX_HTML: &str = include_str!("index.html");
+
+/// Serves the muxed stream from ffmpeg to any number of http clients.
+#[allow(unused)]
+pub struct HttpServer {
+    dm: Rc<Danmaku>,
+    ctl: Rc<ControlServer>,
+    hls_dir: PathBuf,
+    ipc_manager: Rc<IPCManager>,
+    cm: Rc<ConfigManager>,
+    mtx: async_channel::Sender<DMLMessage>,
+    fanout: Fanout,
+}
+
+impl HttpServer {
+    pub fn new(
+        dm: Rc<Danmaku>, ctl: Rc<ControlServer>, cm: Rc<ConfigManager>, im: Rc<IPCManager>,
+        mtx: async_channel::Sender<DMLMessage>,
+    ) -> Self {
+        Self {
+            dm,
+            ctl,
+            hls_dir: im.get_hls_dir_path(),
+            ipc_manager: im,
+            cm,
+            mtx,
+            fanout: Fanout::new(),
+        }
+    }
+
+    // "http://0.0.0.0:8080/live" listens on "0.0.0.0:8080", any path the web page does not take serves the stream
+    fn listen_addr(&self) -> String {
+        let addr = self.cm.http_address.as_deref().unwrap_or("");
+        let addr = addr.split_once("://").map(|it| it.1).unwrap_or(addr);
+        addr.split('/').next().unwrap_or("").to_string()
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let listener = TcpListener::bind(self.listen_addr()).await?;
+        info!("http server listening on {}", listener.local_addr()?);
+        let mut tasks = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(_) = tasks.next() => {},
+                it = listener.accept() => {
+                    let (s, addr) = it?;
+                    tasks.push(async move {
+                        let _ = self.handle(s).await.map_err(|e| info!("http client {} error: {}", addr, e));
+                    });
+                }
+            }
+        }
+    }
+
+    async fn handle(&self, mut s: TcpStream) -> Result<()> {
+        let req = Request::read(&mut s).await?;
+        if let Some(token) = self.cm.http_token.as_ref() {
+            if req.token().as_ref() != Some(token) {
+                return request::write_response(&mut s, "401 Unauthorized", "text/plain", b"unauthorized").await;
+            }
+        }
+        let hls = self.cm.http_hls;
+        match (req.method.as_str(), req.path.as_str()) {
+            ("GET", "/metrics") => self.metrics(s).await,
+            // any page the browser opens can post here, the token and the json type keep them out
+            ("POST", "/api") if self.cm.http_token.is_none() => {
+                request::write_response(
+                    &mut s,
+                    "403 Forbidden",
+                    "text/plain",
+                    b"the api needs --http-token",
+                )
+                .await
+            }
+            ("POST", "/api") if !req.is_json() => {
+                request::write_response(
+                    &mut s,
+                    "415 Unsupported Media Type",
+                    "text/plain",
+                    b"expected application/json",
+                )
+                .await
+            }
+            ("POST", "/api") => {
+                // the same commands the control socket takes
+                let resp = match serde_json::from_slice(&req.body) {
+                    Ok(it) => self.ctl.handle(&it).await,
+                    Err(e) => serde_json::json!({"ok": false, "error": e.to_string()}),
+                };
+                request::write_response(
+                    &mut s,
+                    "200 OK",
+                    "application/json",
+                    resp.to_string().as_bytes(),
+                )
+                .await
+            }
+            ("GET", "/" | "/index.html") if hls => {
+                request::write_response(
+                    &mut s,
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    INDEX_HTML.as_bytes(),
+                )
+                .await
+            }
+            ("GET", "/danmaku") if hls => self.danmaku_client(s, &req).await,
+            ("GET", it) if hls && [".m3u8", ".m4s", ".mp4"].iter().any(|ext| it.ends_with(ext)) => {
+                self.hls_file(s, it).await
+            }
+            ("GET" | "HEAD", _) => self.stream_client(s, &req).await,
+            _ => {
+                request::write_response(
+                    &mut s,
+                    "405 Method Not Allowed",
+                    "text/plain",
+                    b"method not allowed",
+                )
+                .await
+            }
+        }
+    }
+
+    /// Serves only "/metrics" on `--metrics-address`, without the token.
+    pub async fn run_metrics(&self) -> Result<()> {
+        let addr = self.cm.metrics_address.as_deref().unwrap_or("");
+        let listener = TcpListener::bind(addr.split_once("://").map(|it| it.1).unwrap_or(addr)).await?;
+        info!("metrics listening on {}", listener.local_addr()?);
+        let mut tasks = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(_) = tasks.next() => {},
+                it = listener.accept() => {
+                    let (mut s, _) = it?;
+                    tasks.push(async move {
+                        let res = match Request::read(&mut s).await {
+                            Ok(req) if req.path.eq("/metrics") => self.metrics(s).await,
+                            Ok(_) => request::write_response(&mut s, "404 Not Found", "text/plain", b"not found").await,
+                            Err(e) => Err(e),
+                        };
+                        let _ = res.map_err(|e| info!("metrics client error: {}", e));
+                    });
+                }
+            }
+        }
+    }
+
+    async fn metrics(&self, mut s: TcpStream) -> Result<()> {
+        let body = self.cm.metrics.render(&self.cm);
+        request::write_response(
+            &mut s,
+            "200 OK",
+            "text/plain; version=0.0.4",
+            body.as_bytes(),
+        )
+        .await
+    }
+
+    async fn stream_client(&self, mut s: TcpStream, req: &Request) -> Result<()> {
+        request::write_head(
+            &mut s,
+            "200 OK",
+            &[("Content-Type", "video/x-matroska"), ("Cache-Control", "no-cache")],
+        )
+        .await?;
+        if req.method.eq("HEAD") {
+            return Ok(());
+        }
+        let mut rx = self.fanout.subscribe();
+        info!("http client connected: {:?}", s.peer_addr());
+        while let Some(data) = rx.recv().await {
+            s.write_all(&data).await?;
+        }
+        Ok(())
+    }
+
+    async fn hls_file(&self, mut s: TcpStream, path: &str) -> Result<()> {
+        let name = path.trim_start_matches('/');
+        let data = if name.contains('/') || name.starts_with('.') {
+            None
+        } else {
+            tokio::fs::read(self.hls_dir.join(name)).await.ok()
+        };
+        let content_type = match name.rsplit('.').next() {
+            Some("m3u8") => "application/vnd.apple.mpegurl",
+            Some("m4s") => "video/iso.segment",
+            _ => "video/mp4",
+        };
+        match data {
+            Some(it) => request::write_response(&mut s, "200 OK", content_type, &it).await,
+            None => request::write_response(&mut s, "404 Not Found", "text/plain", b"not found").await,
+        }
+    }
+
+    // every danmaku that goes into the subtitle track, as json text messages
+    async fn danmaku_client(&self, mut s: TcpStream, req: &Request) -> Result<()> {
+        let key = req.header("sec-websocket-key").ok_or_else(|| dmlerr!())?;
+        request::write_upgrade(&mut s, &derive_accept_key(key.as_bytes())).await?;
+        let mut events = self.dm.subscribe();
+        let ws = WebSocketStream::from_raw_socket(s, Role::Server, None).await;
+        let (mut ws_write, mut ws_read) = ws.split();
+        ws_write.send(Message::Text(self.dm.overlay_config())).await?;
+        loop {
+            tokio::select! {
+                it = events.recv() => match it {
+                    Ok(it) => ws_write.send(Message::Text(it)).await?,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                },
+                it = ws_read.next() => match it {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the ffmpeg output until it ends, the clients are closed with it.
+    pub async fn feed_task(&self) -> Result<()> {
+        let in_stream = crate::ffmpeg::connect_output(&self.ipc_manager.get_f2m_socket_addr()).await?;
+        let hls_task = async {
+            if self.cm.http_hls {
+                let _ = self.hls_task().await.map_err(|e| info!("hls error: {}", e));
+            }
+        };
+        let (res, _) = tokio::join!(self.read_output(in_stream), hls_task);
+        res
+    }
+
+    // the hls muxer is just another client of the stream
+    async fn hls_task(&self) -> Result<()> {
+        let _ = tokio::fs::remove_dir_all(&self.hls_dir).await;
+        tokio::fs::create_dir_all(&self.hls_dir).await?;
+        let mut rx = self.fanout.subscribe();
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-y", "-hide_banner", "-loglevel", "error"]);
+        cmd.args(["-i", "-"]);
+        cmd.args(["-map", "0:v:0?", "-map", "0:a:0?", "-c", "copy"]);
+        // fmp4 takes the codecs mpeg-ts does not, like vp9 and opus from youtube
+        cmd.args(["-f", "hls", "-hls_time", "2", "-hls_list_size", "6"]);
+        cmd.args([
+            "-hls_flags",
+            "delete_segments+omit_endlist",
+            "-hls_segment_type",
+            "fmp4",
+        ]);
+        cmd.arg("-hls_segment_filename").arg(self.hls_dir.join("seg%d.m4s"));
+        cmd.arg(self.hls_dir.join("live.m3u8"));
+        let mut ff = cmd.stdin(std::process::Stdio::piped()).kill_on_drop(true).spawn()?;
+        let mut stdin = ff.stdin.take().ok_or_else(|| dmlerr!())?;
+        while let Some(data) = rx.recv().await {
+            stdin.write_all(&data).await?;
+        }
+        drop(stdin);
+        ff.wait().await?;
+        Ok(())
+    }
+
+    async fn read_output(&self, mut in_stream: TcpStream) -> Result<()> {
+        let mut parser = MkvParser::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let res = async {
+            loop {
+                let n = in_stream.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                parser.push(&buf[..n]);
+                while let Some(item) = parser.next_item()? {
+                    self.fanout.broadcast(&item);
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        self.fanout.clear();
+        res
+    }
+}
@@ -0,0 +1,122 @@
+use crate::dmlerr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_BODY_LEN: usize = 65536;
+
+#[allow(unused)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads the request line, headers and a body with a `Content-Length`.
+    pub async fn read(s: &mut TcpStream) -> anyhow::Result<Self> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        let end = loop {
+            let n = s.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(dmlerr!());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(it) = buf.windows(4).position(|it| it == b"\r\n\r\n") {
+                break it;
+            }
+            if buf.len() > MAX_HEAD_LEN {
+                return Err(anyhow::anyhow!("request head too large"));
+            }
+        };
+        let head = String::from_utf8_lossy(&buf[..end]);
+        let mut lines = head.split("\r\n");
+        let mut it = lines.next().ok_or_else(|| dmlerr!())?.split(' ');
+        let method = it.next().ok_or_else(|| dmlerr!())?.to_string();
+        let target = it.next().ok_or_else(|| dmlerr!())?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|it| it.split_once(':'))
+            .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+            .collect();
+        let body_len: usize =
+            headers.iter().find(|it| it.0.eq("content-length")).and_then(|it| it.1.parse().ok()).unwrap_or(0);
+        if body_len > MAX_BODY_LEN {
+            return Err(anyhow::anyhow!("request body too large"));
+        }
+        let mut body = buf[end + 4..].to_vec();
+        while body.len() < body_len {
+            let n = s.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(dmlerr!());
+            }
+            body.extend_from_slice(&chunk[..n]);
+        }
+        body.truncate(body_len);
+        Ok(Request {
+            method,
+            path: urlencoding::decode(path).map(|it| it.into_owned()).unwrap_or_else(|_| path.to_string()),
+            query,
+            headers,
+            body,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|it| it.0.eq(name)).map(|it| it.1.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|it| it.0.eq(name)).map(|it| it.1.as_str())
+    }
+
+    pub fn is_json(&self) -> bool {
+        let ct = self.header("content-type").and_then(|it| it.split(';').next()).unwrap_or("");
+        ct.trim().eq_ignore_ascii_case("application/json")
+    }
+
+    /// The token from `?token=`, an `Authorization: Bearer` header or the cookie the web page sets.
+    pub fn token(&self) -> Option<String> {
+        if let Some(it) = self.query("token").or_else(|| self.header("authorization")?.strip_prefix("Bearer ")) {
+            return Some(it.to_string());
+        }
+        let cookie = self.header("cookie")?.split(';').find_map(|it| it.trim().strip_prefix("dml_token="))?;
+        urlencoding::decode(cookie).ok().map(|it| it.into_owned())
+    }
+}
+
+/// Writes the status line and headers, the body is written by the caller until the connection closes.
+pub async fn write_head(s: &mut TcpStream, status: &str, headers: &[(&str, &str)]) -> anyhow::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (k, v) in headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    s.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn write_upgrade(s: &mut TcpStream, accept: &str) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    s.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn write_response(s: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> anyhow::Result<()> {
+    let len = body.len().to_string();
+    write_head(
+        s,
+        status,
+        &[("Content-Type", content_type), ("Content-Length", &len)],
+    )
+    .await?;
+    s.write_all(body).await?;
+    Ok(())
+}
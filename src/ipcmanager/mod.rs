@@ -1,6 +1,7 @@
 use crate::config::{ConfigManager, Platform};
 use crate::dmlerr;
 use anyhow::Result;
+use std::path::PathBuf;
 use std::rc::Rc;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -15,6 +16,7 @@ pub struct IPCManager {
     base_uuid: String,
     base_socket_dir: String,
     f2m_port: u16,
+    rec_port: u16,
     danmaku_port: u16,
     video_port: u16,
     audio_port: u16,
@@ -32,6 +34,7 @@ impl IPCManager {
             base_uuid,
             base_socket_dir: "/tmp".into(),
             f2m_port: 0,
+            rec_port: 0,
             danmaku_port: 0,
             video_port: 0,
             audio_port: 0,
@@ -57,6 +60,11 @@ impl IPCManager {
             ))
             .await;
         }
+        let _ = tokio::fs::remove_file(self.get_ctl_socket_path()).await;
+        let _ = tokio::fs::remove_file(self.get_cover_path()).await;
+        let _ = tokio::fs::remove_dir_all(self.get_replay_dir_path()).await;
+        let _ = tokio::fs::remove_dir_all(self.get_timeshift_dir_path()).await;
+        let _ = tokio::fs::remove_dir_all(self.get_hls_dir_path()).await;
         Ok(())
     }
 
@@ -88,6 +96,8 @@ impl IPCManager {
     async fn init_f2m(&mut self) -> Result<()> {
         let (_, p) = Self::get_tcp_listener().await;
         self.f2m_port = p;
+        let (_, p) = Self::get_tcp_listener().await;
+        self.rec_port = p;
         Ok(())
     }
 
@@ -108,6 +118,33 @@ impl IPCManager {
         format!("{}/dml-{}-mpv", &self.base_socket_dir, &self.base_uuid)
     }
 
+    pub fn get_replay_dir_path(&self) -> PathBuf {
+        // keep it on disk, /tmp may well be in memory
+        let proj_dirs = directories::ProjectDirs::from("com", "THMonster", "dmlive").unwrap();
+        proj_dirs.cache_dir().join(format!("replay-{}", &self.base_uuid))
+    }
+
+    pub fn get_timeshift_dir_path(&self) -> PathBuf {
+        let proj_dirs = directories::ProjectDirs::from("com", "THMonster", "dmlive").unwrap();
+        proj_dirs.cache_dir().join(format!("timeshift-{}", &self.base_uuid))
+    }
+
+    pub fn get_ctl_socket_path(&self) -> String {
+        format!("{}/dml-{}-ctl", &self.base_socket_dir, &self.base_uuid)
+    }
+
+    pub fn get_hls_dir_path(&self) -> PathBuf {
+        let proj_dirs = directories::ProjectDirs::from("com", "THMonster", "dmlive").unwrap();
+        proj_dirs.cache_dir().join(format!("hls-{}", &self.base_uuid))
+    }
+
+    pub fn get_cover_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/dml-{}-cover",
+            &self.base_socket_dir, &self.base_uuid
+        ))
+    }
+
     pub fn get_f2m_socket_path(&self) -> String {
         // if self.plat == 0 {
         //     format!(
@@ -119,6 +156,19 @@ impl IPCManager {
         // }
     }
 
+    pub fn get_f2m_socket_addr(&self) -> String {
+        format!("127.0.0.1:{}", &self.f2m_port)
+    }
+
+    // the recording side of play and record
+    pub fn get_rec_socket_path(&self) -> String {
+        format!("tcp://127.0.0.1:{}", &self.rec_port)
+    }
+
+    pub fn get_rec_socket_addr(&self) -> String {
+        format!("127.0.0.1:{}", &self.rec_port)
+    }
+
     // pub fn get_stream_socket_path(&self) -> String {
     //     // format!("unix://{}/dml-{}-s", &self.base_socket_dir, &self.base_uuid)
     //     format!("tcp://127.0.0.1:{}", &self.stream_port)
@@ -1,40 +1,107 @@
 mod config;
+mod control;
 mod danmaku;
 mod dmlive;
 mod ffmpeg;
+mod httpserver;
 mod ipcmanager;
+mod metrics;
 mod mpv;
 mod streamer;
 mod streamfinder;
 mod utils;
 
 use crate::config::ConfigManager;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::*;
 use std::rc::Rc;
 use tokio::runtime::Builder;
 
-#[derive(Parser)]
-#[derive(Debug)]
-#[clap(author, version, about, long_about = None)]
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Commands>,
+
     /// Set the http url
-    #[clap(short = 'u', long, value_parser, value_name = "URL")]
-    url: String,
+    #[clap(
+        short = 'u',
+        long,
+        value_parser,
+        value_name = "URL",
+        required = true
+    )]
+    url: Option<String>,
 
     #[clap(short = 'r', long, action)]
     record: bool,
 
+    /// Push the stream to an ffmpeg output url instead of playing it, e.g. "rtmp://host/live/key" or "out.mkv"
+    #[clap(short = 'o', long, value_parser, value_name = "URL")]
+    output: Option<String>,
+
+    /// Burn danmaku into the picture of --output with mpv, ffmpeg can not render a live ass track. This re-encodes
+    #[clap(long = "burn-danmaku", action)]
+    burn_danmaku: bool,
+
+    /// Play in mpv and record at the same time from the same stream
+    #[clap(long = "play-record", action)]
+    play_record: bool,
+
+    /// Start from the earliest segment the live playlist offers, always on when recording
+    #[clap(long = "from-start", action)]
+    from_start: bool,
+
+    /// Start playback behind the live edge, e.g. "10m" (youtube only)
+    #[clap(long, value_parser, value_name = "DURATION")]
+    rewind: Option<String>,
+
+    /// Stream quality: best, worst, source, or a height like "720p" / "1080p60"
+    #[clap(long, value_parser, value_name = "QUALITY")]
+    quality: Option<String>,
+
+    /// Buffer the live stream locally so it can be paused and rewound, e.g. "30m", "2GB" or "30m,2GB"
+    #[clap(long, value_parser, value_name = "DURATION|SIZE")]
+    timeshift: Option<String>,
+
+    /// Play or record audio only, danmaku are printed to the terminal
+    #[clap(long = "audio-only", action)]
+    audio_only: bool,
+
+    /// Start a new recording part after this long, e.g. "1h"
+    #[clap(long = "split-every", value_parser, value_name = "DURATION")]
+    split_every: Option<String>,
+
+    /// Start a new recording part once it grows this big, e.g. "4G"
+    #[clap(long = "split-size", value_parser, value_name = "SIZE")]
+    split_size: Option<String>,
+
     #[clap(short = 'w', long = "wait-interval", value_parser)]
     wait_interval: Option<u64>,
 
     #[clap(long = "log-level", default_value_t = 3, value_parser)]
     log_level: u8,
 
-    /// Serve as a http server
+    /// Serve the stream as a http server, without recording it
     #[clap(long = "http-address", value_parser)]
     http_address: Option<String>,
 
+    /// Require this token from http clients, as "?token=" or a bearer token. "POST /api" is only served with one
+    #[clap(long = "http-token", value_parser, value_name = "TOKEN")]
+    http_token: Option<String>,
+
+    /// Serve a web page with HLS and a danmaku overlay on the http address, the stream moves to "/stream"
+    #[clap(long = "http-hls", action)]
+    http_hls: bool,
+
+    /// Serve prometheus metrics on "/metrics" at this address, they are on the http address too
+    #[clap(
+        long = "metrics-address",
+        value_parser,
+        value_name = "ADDRESS"
+    )]
+    metrics_address: Option<String>,
+
     /// Do not print danmaku
     #[clap(short = 'q', long, action)]
     quiet: bool,
@@ -49,6 +116,21 @@ pub struct Args {
     // cookies_from_browser: Option<String>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Control a running dmlive: status, reload, quality, font-scale, font-alpha, speed, nick, filter, page, next,
+    /// back, clip or quit
+    Ctl {
+        /// The control socket, needed when more than one dmlive is running
+        #[clap(long, value_parser, value_name = "PATH")]
+        socket: Option<String>,
+
+        /// The command and its arguments, e.g. "quality 720p"
+        #[clap(required = true)]
+        args: Vec<String>,
+    },
+}
+
 fn main() {
     let args = Args::parse();
     info!("command line args: {:?}", args);
@@ -61,6 +143,15 @@ fn main() {
     };
     env_logger::Builder::new().filter(None, log_level).init();
 
+    if let Some(Commands::Ctl { socket, args }) = args.command {
+        let res = Builder::new_current_thread().enable_all().build().unwrap().block_on(control::run_ctl(socket, &args));
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     Builder::new_current_thread().enable_all().build().unwrap().block_on(async move {
         let proj_dirs = directories::ProjectDirs::from("com", "THMonster", "dmlive").unwrap();
         let d = proj_dirs.config_dir();
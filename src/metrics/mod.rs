@@ -0,0 +1,161 @@
+use crate::config::ConfigManager;
+use std::{cell::Cell, fmt::Write};
+
+// upper bounds of the segment fetch latency buckets, in seconds
+const FETCH_BUCKETS: [f64; 8] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+pub struct Counter(Cell<u64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.set(self.0.get() + n);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [Counter; FETCH_BUCKETS.len()],
+    sum: Cell<f64>,
+    count: Counter,
+}
+
+impl Histogram {
+    pub fn observe(&self, secs: f64) {
+        for (i, it) in FETCH_BUCKETS.iter().enumerate() {
+            if secs <= *it {
+                self.buckets[i].inc();
+            }
+        }
+        self.sum.set(self.sum.get() + secs);
+        self.count.inc();
+    }
+}
+
+/// Counters and gauges of this room, rendered in the prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pub bytes_downloaded: Counter,
+    pub segment_fetch: Histogram,
+    pub segment_failures: Counter,
+    pub watchdog_trips: Counter,
+    pub ffmpeg_restarts: Counter,
+    pub danmaku_received: Counter,
+    pub danmaku_dropped: Counter,
+    pub danmaku_rendered: Counter,
+    pub ws_reconnects: Counter,
+    pub danmaku_queue: Cell<u64>,
+    pub live: Cell<bool>,
+}
+
+impl Metrics {
+    pub fn render(&self, cm: &ConfigManager) -> String {
+        let labels = format!(
+            "site=\"{}\",room=\"{}\"",
+            cm.site.name(),
+            escape(&cm.room_id())
+        );
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP dmlive_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE dmlive_{} {}", name, kind);
+            let _ = writeln!(out, "dmlive_{}{{{}}} {}", name, labels, value);
+        };
+        metric(
+            "bytes_downloaded_total",
+            "counter",
+            "Bytes of stream data downloaded.",
+            self.bytes_downloaded.get().to_string(),
+        );
+        metric(
+            "segment_fetch_failures_total",
+            "counter",
+            "Segments given up after all retries.",
+            self.segment_failures.get().to_string(),
+        );
+        metric(
+            "watchdog_trips_total",
+            "counter",
+            "Times the stream stalled long enough for the watchdog.",
+            self.watchdog_trips.get().to_string(),
+        );
+        metric(
+            "ffmpeg_restarts_total",
+            "counter",
+            "Times ffmpeg was started again after the first.",
+            self.ffmpeg_restarts.get().to_string(),
+        );
+        metric(
+            "danmaku_received_total",
+            "counter",
+            "Danmaku received from the site.",
+            self.danmaku_received.get().to_string(),
+        );
+        metric(
+            "danmaku_dropped_total",
+            "counter",
+            "Danmaku dropped by the filters.",
+            self.danmaku_dropped.get().to_string(),
+        );
+        metric(
+            "danmaku_rendered_total",
+            "counter",
+            "Danmaku written to the subtitle track.",
+            self.danmaku_rendered.get().to_string(),
+        );
+        metric(
+            "websocket_reconnects_total",
+            "counter",
+            "Times the danmaku connection was made again.",
+            self.ws_reconnects.get().to_string(),
+        );
+        metric(
+            "danmaku_queue_depth",
+            "gauge",
+            "Danmaku waiting for a free line.",
+            self.danmaku_queue.get().to_string(),
+        );
+        metric(
+            "live",
+            "gauge",
+            "1 while the stream is being pulled.",
+            (self.live.get() as u8).to_string(),
+        );
+        let h = &self.segment_fetch;
+        let name = "dmlive_segment_fetch_seconds";
+        let _ = writeln!(out, "# HELP {} Time to fetch a stream segment.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (i, it) in FETCH_BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name,
+                labels,
+                it,
+                h.buckets[i].get()
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name,
+            labels,
+            h.count.get()
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, h.sum.get());
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, h.count.get());
+        out
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -1,3 +1,5 @@
+use crate::config::Quality;
+
 pub struct CmdParser {
     pub restart: bool,
     pub next: bool,
@@ -10,6 +12,10 @@ pub struct CmdParser {
     pub fa: Option<f64>,
     pub speed: Option<u64>,
     pub page: Option<u64>,
+    pub rewind: Option<u64>,
+    pub live: bool,
+    pub quality: Option<Quality>,
+    pub clip: Option<u64>,
 }
 
 impl CmdParser {
@@ -25,6 +31,10 @@ impl CmdParser {
         let mut fa = None;
         let mut speed = None;
         let mut page = None;
+        let mut rewind = None;
+        let mut live = false;
+        let mut quality = None;
+        let mut clip = None;
         if s.starts_with("dml:") {
             let s = &s[4..];
             let cmds: Vec<&str> = s.split(',').collect();
@@ -43,6 +53,10 @@ impl CmdParser {
                     nick = true;
                 } else if cmd.trim().eq("fps") {
                     fps = true;
+                } else if cmd.trim().eq("live") {
+                    live = true;
+                } else if cmd.trim().eq("clip") {
+                    clip = Some(60);
                 }
                 let subcmds: Vec<&str> = cmd.split('=').collect();
                 let mut iter = subcmds.iter();
@@ -71,6 +85,17 @@ impl CmdParser {
                         Ok(it) => Some(it),
                         Err(_) => None,
                     };
+                } else if arg1.eq("rewind") {
+                    let arg2 = *iter.next().unwrap_or(&"");
+                    rewind = crate::utils::parse_duration_secs(arg2);
+                } else if arg1.eq("quality") {
+                    let arg2 = *iter.next().unwrap_or(&"");
+                    quality = Quality::parse(arg2);
+                } else if arg1.eq("clip") {
+                    let arg2 = *iter.next().unwrap_or(&"");
+                    if !arg2.is_empty() {
+                        clip = crate::utils::parse_duration_secs(arg2);
+                    }
                 }
             }
         }
@@ -86,6 +111,10 @@ impl CmdParser {
             fa,
             speed,
             page,
+            rewind,
+            live,
+            quality,
+            clip,
         }
     }
 }
@@ -0,0 +1,199 @@
+use crate::dmlerr;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::oneshot,
+};
+
+#[allow(unused)]
+pub enum MpvCommand<'a> {
+    LoadFile(&'a str),
+    Stop,
+    GetProperty(&'a str),
+    SetProperty(&'a str, Value),
+    ObserveProperty(u64, &'a str),
+    Keybind(&'a str, &'a str),
+    ShowText(&'a str, u64), // text, duration in ms
+    Seek(f64),              // absolute, in seconds
+    SubAdd(&'a str),
+    SubRemove(u64),
+}
+
+impl MpvCommand<'_> {
+    fn args(&self) -> Value {
+        match self {
+            MpvCommand::LoadFile(it) => json!(["loadfile", it]),
+            MpvCommand::Stop => json!(["stop"]),
+            MpvCommand::GetProperty(it) => json!(["get_property", it]),
+            MpvCommand::SetProperty(k, v) => json!(["set_property", k, v]),
+            MpvCommand::ObserveProperty(id, it) => json!(["observe_property", id, it]),
+            MpvCommand::Keybind(k, v) => json!(["keybind", k, v]),
+            MpvCommand::ShowText(it, d) => json!(["show-text", it, d]),
+            MpvCommand::Seek(it) => json!(["seek", it.to_string(), "absolute"]),
+            MpvCommand::SubAdd(it) => json!(["sub-add", it]),
+            MpvCommand::SubRemove(it) => json!(["sub-remove", it.to_string()]),
+        }
+    }
+
+    // these may block mpv for a while
+    fn is_async(&self) -> bool {
+        matches!(
+            self,
+            MpvCommand::LoadFile(_) | MpvCommand::GetProperty(_) | MpvCommand::SubAdd(_) | MpvCommand::SubRemove(_)
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VideoParams {
+    pub w: u64,
+    pub h: u64,
+}
+
+#[allow(unused)]
+pub enum MpvEvent {
+    FileLoaded,
+    EndFile { reason: String },
+    ClientMessage(Vec<String>),
+    PropertyChange { name: String, data: Value },
+    Other(String),
+}
+
+/// The json ipc protocol of mpv. Commands queue up until a connection writes them out,
+/// replies are matched to their requests by id.
+pub struct MpvIpc {
+    next_id: Cell<u64>,
+    pending: RefCell<HashMap<u64, oneshot::Sender<Result<Value>>>>,
+    tx: async_channel::Sender<String>,
+    rx: async_channel::Receiver<String>,
+}
+
+impl MpvIpc {
+    pub fn new() -> Self {
+        let (tx, rx) = async_channel::unbounded();
+        Self {
+            // mpv replies with 0 to commands without an id
+            next_id: Cell::new(1),
+            pending: RefCell::new(HashMap::new()),
+            tx,
+            rx,
+        }
+    }
+
+    fn alloc_id(&self) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        id
+    }
+
+    fn encode(cmd: &MpvCommand, id: Option<u64>) -> String {
+        let mut j = json!({ "command": cmd.args() });
+        if let Some(it) = id {
+            j["request_id"] = it.into();
+        }
+        if cmd.is_async() {
+            j["async"] = true.into();
+        }
+        format!("{}\n", j)
+    }
+
+    /// Queues a command without waiting for mpv to answer.
+    pub async fn send(&self, cmd: MpvCommand<'_>) -> Result<()> {
+        self.tx.send(Self::encode(&cmd, None)).await?;
+        Ok(())
+    }
+
+    /// Sends a command and resolves to the `data` of its reply.
+    pub async fn request(&self, cmd: MpvCommand<'_>) -> Result<Value> {
+        let id = self.alloc_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending.borrow_mut().insert(id, tx);
+        if let Err(e) = self.tx.send(Self::encode(&cmd, Some(id))).await {
+            self.pending.borrow_mut().remove(&id);
+            return Err(e.into());
+        }
+        rx.await.map_err(|_| anyhow::anyhow!("mpv disconnected"))?
+    }
+
+    pub async fn get_property<T: DeserializeOwned>(&self, name: &str) -> Result<T> {
+        let data = self.request(MpvCommand::GetProperty(name)).await?;
+        Ok(serde_json::from_value(data)?)
+    }
+
+    pub async fn set_property(&self, name: &str, value: impl Into<Value>) -> Result<()> {
+        self.send(MpvCommand::SetProperty(name, value.into())).await
+    }
+
+    /// Changes of `name` come back as `MpvEvent::PropertyChange`, starting with its current value.
+    pub async fn observe_property(&self, name: &str) -> Result<()> {
+        self.send(MpvCommand::ObserveProperty(self.alloc_id(), name)).await
+    }
+
+    /// Writes the queued commands to a connection until it fails.
+    pub async fn write_task(&self, mut w: impl AsyncWrite + Unpin) {
+        while let Ok(s) = self.rx.recv().await {
+            if w.write_all(s.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Drops the queued commands, for when there is no mpv to take them.
+    pub async fn discard_task(&self) {
+        while self.rx.recv().await.is_ok() {
+            self.pending.borrow_mut().clear();
+        }
+    }
+
+    /// Fails the requests still waiting, their replies will not come.
+    pub fn reset(&self) {
+        self.pending.borrow_mut().clear();
+    }
+
+    /// Resolves the request a reply belongs to, or turns the line into an event.
+    pub fn handle_line(&self, line: &str) -> Result<Option<MpvEvent>> {
+        let j: Value = serde_json::from_str(line)?;
+        if let Some(id) = j.pointer("/request_id").and_then(|it| it.as_u64()) {
+            if let Some(tx) = self.pending.borrow_mut().remove(&id) {
+                let res = match j.pointer("/error").and_then(|it| it.as_str()) {
+                    Some("success") => Ok(j.pointer("/data").cloned().unwrap_or(Value::Null)),
+                    Some(it) => Err(anyhow::anyhow!("mpv: {}", it)),
+                    None => Err(dmlerr!()),
+                };
+                let _ = tx.send(res);
+            }
+            return Ok(None);
+        }
+        let event = match j.pointer("/event").and_then(|it| it.as_str()) {
+            Some(it) => it,
+            None => return Ok(None),
+        };
+        let ev = match event {
+            "file-loaded" => MpvEvent::FileLoaded,
+            "end-file" => MpvEvent::EndFile {
+                reason: j.pointer("/reason").and_then(|it| it.as_str()).unwrap_or("").to_string(),
+            },
+            "client-message" => MpvEvent::ClientMessage(
+                j.pointer("/args")
+                    .and_then(|it| it.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|it| it.as_str().map(|it| it.to_string()))
+                    .collect(),
+            ),
+            "property-change" => MpvEvent::PropertyChange {
+                name: j.pointer("/name").and_then(|it| it.as_str()).unwrap_or("").to_string(),
+                data: j.pointer("/data").cloned().unwrap_or(Value::Null),
+            },
+            it => MpvEvent::Other(it.to_string()),
+        };
+        Ok(Some(ev))
+    }
+}
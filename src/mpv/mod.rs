@@ -1,36 +1,31 @@
 pub mod cmdparser;
+pub mod ipc;
 use crate::config::Platform;
 use crate::dmlerr;
 use crate::ipcmanager::IPCManager;
 use crate::{config::ConfigManager, dmlive::DMLMessage, utils::gen_ua};
 use anyhow::Result;
 use futures::StreamExt;
+use ipc::{MpvCommand, MpvEvent, MpvIpc, VideoParams};
 use log::info;
 use std::cell::Cell;
 use std::rc::Rc;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt},
-    net::UnixStream,
-    process::Command,
-};
+use tokio::{io::AsyncBufReadExt, net::UnixStream, process::Command};
 
 pub struct MpvControl {
     last_rpc_ts: Cell<i64>,
     ipc_manager: Rc<IPCManager>,
     cm: Rc<ConfigManager>,
     mtx: async_channel::Sender<DMLMessage>,
-    mpv_command_tx: async_channel::Sender<String>,
-    mpv_command_rx: async_channel::Receiver<String>,
+    ipc: MpvIpc,
 }
 impl MpvControl {
     pub fn new(cm: Rc<ConfigManager>, im: Rc<IPCManager>, mtx: async_channel::Sender<DMLMessage>) -> Self {
-        let (tx, rx) = async_channel::unbounded();
         Self {
             ipc_manager: im,
             cm,
             mtx,
-            mpv_command_tx: tx,
-            mpv_command_rx: rx,
+            ipc: MpvIpc::new(),
             last_rpc_ts: Cell::new(0),
         }
     }
@@ -54,30 +49,43 @@ impl MpvControl {
             "--input-ipc-server={}",
             self.ipc_manager.get_mpv_socket_path()
         ));
+        if self.cm.audio_only {
+            ret.args(["--no-video", "--geometry=480x120"]);
+        }
+        if let Some((secs, bytes)) = self.cm.timeshift {
+            // mpv keeps reading into its disk cache while paused, and can seek anywhere inside it
+            let dir = self.ipc_manager.get_timeshift_dir_path();
+            tokio::fs::create_dir_all(&dir).await?;
+            ret.args(["--cache-on-disk=yes", "--force-seekable=yes"]);
+            ret.arg("--demuxer-seekable-cache=yes");
+            ret.arg(format!("--demuxer-cache-dir={}", dir.to_string_lossy()));
+            ret.arg(format!("--demuxer-max-bytes={}", bytes));
+            ret.arg(format!("--demuxer-max-back-bytes={}", bytes));
+            ret.arg(format!("--cache-secs={}", secs));
+        }
         Ok(ret)
     }
 
     pub async fn reload_edl_video(&self, urls: &Vec<String>) -> Result<()> {
-        let edl = format!(
-            "edl://!no_clip;!no_chapters;%{0}%{1};!new_stream;!no_clip;!no_chapters;%{2}%{3}",
-            urls[2].chars().count(),
-            urls[2],
-            urls[1].chars().count(),
-            urls[1]
-        );
+        let edl = if self.cm.audio_only {
+            format!(
+                "edl://!no_clip;!no_chapters;%{0}%{1}",
+                urls[2].chars().count(),
+                urls[2]
+            )
+        } else {
+            format!(
+                "edl://!no_clip;!no_chapters;%{0}%{1};!new_stream;!no_clip;!no_chapters;%{2}%{3}",
+                urls[2].chars().count(),
+                urls[2],
+                urls[1].chars().count(),
+                urls[1]
+            )
+        };
         info!("load video: {}--{}", &edl, self.cm.title.borrow());
-        self.mpv_command_tx
-            .send(format!(
-                "{{ \"command\": [\"loadfile\", \"{}\"], \"async\": true }}\n",
-                &edl
-            ))
-            .await?;
-        self.mpv_command_tx
-            .send(format!(
-                "{{ \"command\": [\"set_property\", \"force-media-title\", \"{}\"] }}\n",
-                self.cm.title.borrow().replace(r#"""#, r#"\""#)
-            ))
-            .await?;
+        self.ipc.send(MpvCommand::LoadFile(&edl)).await?;
+        let title = self.cm.title.borrow().clone();
+        self.ipc.set_property("force-media-title", title).await?;
         Ok(())
     }
 
@@ -85,190 +93,161 @@ impl MpvControl {
         if self.cm.plat == Platform::Android {
             Command::new("termux-open").arg(self.ipc_manager.get_f2m_socket_path()).spawn()?;
         } else {
-            self.mpv_command_tx
-                .send(format!(
-                    "{{ \"command\": [\"loadfile\", \"{}\"] }}\n",
-                    self.ipc_manager.get_f2m_socket_path()
-                ))
-                .await?;
+            let f2m = self.ipc_manager.get_f2m_socket_path();
+            self.ipc.send(MpvCommand::LoadFile(&f2m)).await?;
         }
         Ok(())
     }
 
-    // pub async fn quit(&self) -> Result<()> {
-    //     self.mpv_command_tx.send("{ \"command\": [\"quit\"] }\n".into()).await?;
-    //     Ok(())
-    // }
+    pub async fn show_text(&self, text: &str) -> Result<()> {
+        self.ipc.send(MpvCommand::ShowText(text, 3000)).await
+    }
+
+    pub async fn catch_up(&self) -> Result<()> {
+        // the end of the timeshift buffer is as live as it gets
+        let t: f64 = self.ipc.get_property("demuxer-cache-time").await?;
+        self.ipc.send(MpvCommand::Seek((t - 1.0).max(0.0))).await?;
+        self.ipc.set_property("pause", false).await
+    }
 
     pub async fn stop(&self) -> Result<()> {
-        self.mpv_command_tx.send("{ \"command\": [\"stop\"] }\n".into()).await?;
-        Ok(())
+        self.ipc.send(MpvCommand::Stop).await
     }
 
     pub async fn init_mpv_rpc(&self) -> Result<()> {
-        self.mpv_command_tx
-            .send(
-                r#"{ "command": ["keybind", "alt+r", "script-message dml:r"] }
-                { "command": ["keybind", "alt+z", "script-message dml:fsdown"] }
-                { "command": ["keybind", "alt+x", "script-message dml:fsup"] }
-                { "command": ["keybind", "alt+i", "script-message dml:nick"] }
-                { "command": ["keybind", "alt+b", "script-message dml:back"] }
-                { "command": ["keybind", "alt+n", "script-message dml:next"] }
-                { "command": ["keybind", "alt+f", "script-message dml:fps"] }
-                "#
-                .into(),
-            )
-            .await?;
+        for (k, v) in [
+            ("alt+r", "r"),
+            ("alt+z", "fsdown"),
+            ("alt+x", "fsup"),
+            ("alt+i", "nick"),
+            ("alt+b", "back"),
+            ("alt+n", "next"),
+            ("alt+f", "fps"),
+            ("alt+c", "clip"),
+            ("alt+l", "live"),
+        ] {
+            self.ipc.send(MpvCommand::Keybind(k, &format!("script-message dml:{}", v))).await?;
+        }
+        self.ipc.observe_property("display-fps").await?;
+        self.ipc.observe_property("container-fps").await?;
+        Ok(())
+    }
 
+    // the danmaku of a video are laid out for its size, so they are loaded after it
+    async fn load_video_danmaku(&self) -> Result<()> {
+        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+        let vp: VideoParams = self.ipc.get_property("video-params").await?;
+        let _ = self.mtx.send(DMLMessage::SetVideoInfo((vp.w, vp.h, 0))).await;
+        self.ipc.send(MpvCommand::SubRemove(1)).await?;
+        let danmaku = self.ipc_manager.get_danmaku_socket_path();
+        self.ipc.send(MpvCommand::SubAdd(&danmaku)).await?;
         Ok(())
     }
 
-    async fn handle_mpv_event(&self, line: String) -> Result<()> {
-        let j: serde_json::Value = serde_json::from_str(&line)?;
-        if let Some(rid) = j.pointer("/request_id") {
-            if rid.as_u64().eq(&Some(114)) {
-                let w = j.pointer("/data/w").ok_or_else(|| dmlerr!())?.as_u64().unwrap();
-                let h = j.pointer("/data/h").ok_or_else(|| dmlerr!())?.as_u64().unwrap();
-                if matches!(self.cm.site, crate::config::Site::BiliVideo) {
-                    let _ = self.mtx.send(DMLMessage::SetVideoInfo((w, h, 0))).await;
-                    self.mpv_command_tx
-                        .send(
-                            r#"{ "command": ["sub-remove", "1"], "async": true }
-                              "#
-                            .into(),
-                        )
-                        .await?;
-                    self.mpv_command_tx
-                        .send(format!(
-                            "{{ \"command\": [\"sub-add\", \"{}\"], \"async\": true }}\n",
-                            self.ipc_manager.get_danmaku_socket_path()
-                        ))
-                        .await?;
-                }
-            } else if rid.as_u64().eq(&Some(514)) {
-                match j.pointer("/data") {
-                    Some(it) => match it.as_f64() {
-                        Some(it) => {
-                            self.cm.display_fps.set((it.round() as u64, self.cm.display_fps.get().1));
-                        }
-                        None => {}
-                    },
-                    None => {}
-                }
-            } else if rid.as_u64().eq(&Some(1919)) {
-                match j.pointer("/data") {
-                    Some(it) => match it.as_f64() {
-                        Some(it) => {
-                            if self.cm.display_fps.get().1 == 0 && it < 59.0 {
-                                self.mpv_command_tx
-                                    .send(
-                                        r#"{ "command": ["set_property", "vf", "fps=fps=60:round=near"] }
-                                        "#
-                                        .into(),
-                                    )
-                                    .await?;
-                            }
-                        }
-                        None => {}
-                    },
-                    None => {}
-                }
-            }
-        }
-        let event = j.pointer("/event").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?;
-        if event.eq("end-file") {
-            if matches!(self.cm.site, crate::config::Site::BiliVideo) {
-                if j.pointer("/reason").ok_or_else(|| dmlerr!())?.as_str().unwrap().eq("eof") {
+    async fn handle_mpv_event(&self, ev: MpvEvent) -> Result<()> {
+        match ev {
+            MpvEvent::EndFile { reason } => {
+                if matches!(self.cm.site, crate::config::Site::BiliVideo) && reason.eq("eof") {
                     self.cm.bvideo_info.borrow_mut().current_page += 1;
                     let _ = self.mtx.send(DMLMessage::PlayVideo).await;
                 }
-            } else {
-                // tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-                // let _ = self.reload_video().await;
             }
-        } else if event.eq("file-loaded") {
-            tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-            let _ = self
-                .mpv_command_tx
-                .send(
-                    r#"{ "command": ["get_property", "video-params"], "request_id": 114, "async": true } }
-                    { "command": ["get_property", "display-fps"], "request_id": 514, "async": true }
-                    { "command": ["get_property", "container-fps"], "request_id": 1919, "async": true }
-                    "#
-                    .into(),
-                )
-                .await;
-        } else if event.eq("client-message") {
-            let now = chrono::Utc::now().timestamp_millis();
-            if now - self.last_rpc_ts.get() < 1000 {
-                return Ok(());
-            }
-            self.last_rpc_ts.set(now);
-            let cmds = cmdparser::CmdParser::new(
-                j.pointer("/args/0").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
-            );
-            if cmds.restart {
+            MpvEvent::FileLoaded => {
                 if matches!(self.cm.site, crate::config::Site::BiliVideo) {
-                    let _ = self.mtx.send(DMLMessage::PlayVideo).await;
-                } else {
-                    self.stop().await?;
+                    self.load_video_danmaku().await?;
                 }
             }
-            if cmds.fs.is_some() {
-                let _ = self.mtx.send(DMLMessage::SetFontScale(cmds.fs.unwrap())).await;
-            } else if cmds.fsup {
-                let _ = self.mtx.send(DMLMessage::SetFontScale(self.cm.font_scale.get() + 0.15)).await;
-            } else if cmds.fsdown {
-                let _ = self.mtx.send(DMLMessage::SetFontScale(self.cm.font_scale.get() - 0.15)).await;
-            }
-            if cmds.fa.is_some() {
-                let _ = self.mtx.send(DMLMessage::SetFontAlpha(cmds.fa.unwrap())).await;
-            }
-            if cmds.speed.is_some() {
-                let _ = self.mtx.send(DMLMessage::SetDMSpeed(cmds.speed.unwrap())).await;
-            }
-            if cmds.page.is_some() {
-                self.cm.bvideo_info.borrow_mut().current_page = cmds.page.unwrap() as usize;
-                let _ = self.mtx.send(DMLMessage::PlayVideo).await;
-            }
-            if cmds.nick {
-                let _ = self.mtx.send(DMLMessage::ToggleShowNick).await;
-            }
-            if cmds.back {
-                let p = self.cm.bvideo_info.borrow().current_page.saturating_sub(1);
-                self.cm.bvideo_info.borrow_mut().current_page = if p == 0 { 1 } else { p };
+            MpvEvent::PropertyChange { name, data } => match (name.as_str(), data.as_f64()) {
+                ("display-fps", Some(it)) => {
+                    self.cm.display_fps.set((it.round() as u64, self.cm.display_fps.get().1));
+                }
+                ("container-fps", Some(it)) => {
+                    if self.cm.display_fps.get().1 == 0 && it < 59.0 {
+                        self.ipc.set_property("vf", "fps=fps=60:round=near").await?;
+                    }
+                }
+                _ => {}
+            },
+            MpvEvent::ClientMessage(args) => self.handle_client_message(args.first().ok_or_else(|| dmlerr!())?).await?,
+            MpvEvent::Other(_) => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_client_message(&self, msg: &str) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        if now - self.last_rpc_ts.get() < 1000 {
+            return Ok(());
+        }
+        self.last_rpc_ts.set(now);
+        let cmds = cmdparser::CmdParser::new(msg);
+        if cmds.restart {
+            if matches!(self.cm.site, crate::config::Site::BiliVideo) {
                 let _ = self.mtx.send(DMLMessage::PlayVideo).await;
+            } else if matches!(self.cm.run_mode, crate::config::RunMode::PlayRecord) {
+                // stopping would only hang up on the relay, the recording goes on either way
+                self.reload_video().await?;
+            } else {
+                self.stop().await?;
             }
-            if cmds.next {
-                self.cm.bvideo_info.borrow_mut().current_page += 1;
-                let _ = self.mtx.send(DMLMessage::PlayVideo).await;
+        }
+        if let Some(it) = cmds.fs {
+            let _ = self.mtx.send(DMLMessage::SetFontScale(it)).await;
+        } else if cmds.fsup {
+            let _ = self.mtx.send(DMLMessage::SetFontScale(self.cm.font_scale.get() + 0.15)).await;
+        } else if cmds.fsdown {
+            let _ = self.mtx.send(DMLMessage::SetFontScale(self.cm.font_scale.get() - 0.15)).await;
+        }
+        if let Some(it) = cmds.fa {
+            let _ = self.mtx.send(DMLMessage::SetFontAlpha(it)).await;
+        }
+        if let Some(it) = cmds.speed {
+            let _ = self.mtx.send(DMLMessage::SetDMSpeed(it)).await;
+        }
+        if let Some(it) = cmds.page {
+            self.cm.bvideo_info.borrow_mut().current_page = it as usize;
+            let _ = self.mtx.send(DMLMessage::PlayVideo).await;
+        }
+        if let Some(it) = cmds.rewind {
+            let _ = self.mtx.send(DMLMessage::SetRewind(it)).await;
+        } else if cmds.live {
+            if self.cm.timeshift.is_some() && self.cm.rewind.get() == 0 {
+                self.catch_up().await?;
+            } else {
+                let _ = self.mtx.send(DMLMessage::SetRewind(0)).await;
             }
-            if cmds.fps {
-                let fps: u64 = {
-                    let df = self.cm.display_fps.get();
-                    let i = df.1 as usize % 3;
-                    [df.0, 0u64, 60u64][i]
-                };
-                if fps == 0 {
-                    self.mpv_command_tx
-                        .send(
-                            r#"{ "command": ["set_property", "vf", ""] }
-                            "#
-                            .into(),
-                        )
-                        .await?;
-                } else {
-                    self.mpv_command_tx
-                        .send(format!(
-                            r#"{{ "command": ["set_property", "vf", "fps=fps={}:round=near"] }}
-                        "#,
-                            fps
-                        ))
-                        .await?;
-                }
+        }
+        if let Some(it) = cmds.quality {
+            let _ = self.mtx.send(DMLMessage::SetQuality(it)).await;
+        }
+        if let Some(it) = cmds.clip {
+            let _ = self.mtx.send(DMLMessage::Clip(it)).await;
+        }
+        if cmds.nick {
+            let _ = self.mtx.send(DMLMessage::ToggleShowNick).await;
+        }
+        if cmds.back {
+            let p = self.cm.bvideo_info.borrow().current_page.saturating_sub(1);
+            self.cm.bvideo_info.borrow_mut().current_page = if p == 0 { 1 } else { p };
+            let _ = self.mtx.send(DMLMessage::PlayVideo).await;
+        }
+        if cmds.next {
+            self.cm.bvideo_info.borrow_mut().current_page += 1;
+            let _ = self.mtx.send(DMLMessage::PlayVideo).await;
+        }
+        if cmds.fps {
+            let fps: u64 = {
                 let df = self.cm.display_fps.get();
-                self.cm.display_fps.set((df.0, df.1.saturating_add(1)));
+                let i = df.1 as usize % 3;
+                [df.0, 0u64, 60u64][i]
+            };
+            if fps == 0 {
+                self.ipc.set_property("vf", "").await?;
+            } else {
+                self.ipc.set_property("vf", format!("fps=fps={}:round=near", fps)).await?;
             }
+            let df = self.cm.display_fps.get();
+            self.cm.display_fps.set((df.0, df.1.saturating_add(1)));
         }
         Ok(())
     }
@@ -277,12 +256,7 @@ impl MpvControl {
         let mut mpv = self.create_mpv_command().await?.kill_on_drop(true).spawn().unwrap();
         tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
         let s = UnixStream::connect(self.ipc_manager.get_mpv_socket_path()).await?;
-        let (usocket_read, mut usocket_write) = tokio::io::split(s);
-        let mpv_rpc_write_task = async {
-            while let Ok(s) = self.mpv_command_rx.recv().await {
-                let _ = usocket_write.write_all(s.as_bytes()).await;
-            }
-        };
+        let (usocket_read, usocket_write) = tokio::io::split(s);
         let mpv_rpc_read_task = async {
             let mut tasks = futures::stream::FuturesUnordered::new();
             let mut reader = tokio::io::BufReader::new(usocket_read).lines();
@@ -291,8 +265,12 @@ impl MpvControl {
                     Some(_) = tasks.next() => {},
                     msg = reader.next_line() => {
                         match msg {
-                            Ok(it) => { tasks.push(self.handle_mpv_event(it.unwrap_or("".to_string()))); },
-                            Err(_) => { return; },
+                            Ok(Some(it)) => {
+                                if let Ok(Some(ev)) = self.ipc.handle_line(&it) {
+                                    tasks.push(self.handle_mpv_event(ev));
+                                }
+                            },
+                            _ => { return; },
                         }
                     }
                 }
@@ -301,38 +279,50 @@ impl MpvControl {
         let _ = self.init_mpv_rpc().await;
         // let _ = self.reload_video().await;
         tokio::select! {
-            _ = mpv_rpc_write_task => {},
+            _ = self.ipc.write_task(usocket_write) => {},
             _ = mpv_rpc_read_task => {},
             _ = mpv.wait() => {},
         }
+        self.ipc.reset();
+        Ok(())
+    }
+
+    /// Encodes the ffmpeg output with the danmaku rendered in and pushes it to `--output`, until the stream ends.
+    /// The `subtitles` and `ass` filters of ffmpeg load the whole script from a file before the first frame,
+    /// they can not follow a track that is still being written. mpv renders the live track as it demuxes it.
+    pub async fn run_burn(&self) -> Result<()> {
+        let mut cmd = Command::new("mpv");
+        cmd.args(["--no-terminal", "--sub=1"]);
+        cmd.arg(format!("--oac={}", &self.cm.burn_audio_encoder));
+        cmd.arg(format!("--o={}", self.cm.output.as_deref().unwrap_or("")));
+        if let Some(it) = self.cm.output_format() {
+            cmd.arg(format!("--of={}", it));
+        }
+        cmd.arg(format!("--ovc={}", &self.cm.burn_encoder));
+        cmd.arg(format!("--ovcopts=preset={}", &self.cm.burn_preset));
+        cmd.arg(self.ipc_manager.get_f2m_socket_path());
+        let status = cmd.stdin(std::process::Stdio::null()).kill_on_drop(true).status().await?;
+        info!("burn mpv exited with {}", status);
         Ok(())
     }
 
     pub async fn run_android(&self) -> Result<()> {
         // Command::new("termux-open").arg(self.ipc_manager.get_f2m_socket_path()).spawn().unwrap();
-        while let Ok(s) = self.mpv_command_rx.recv().await {
-            if s.contains("quit") {
-                break;
-            }
-        }
+        self.ipc.discard_task().await;
         Ok(())
     }
 
     pub async fn run(&self) -> Result<()> {
         match self.cm.run_mode {
-            crate::config::RunMode::Play => {
+            crate::config::RunMode::Play | crate::config::RunMode::PlayRecord => {
                 if self.cm.plat == Platform::Android {
                     self.run_android().await?;
                 } else {
                     self.run_normal().await?;
                 }
             }
-            crate::config::RunMode::Record => {
-                while let Ok(s) = self.mpv_command_rx.recv().await {
-                    if s.contains("quit") {
-                        break;
-                    }
-                }
+            crate::config::RunMode::Record | crate::config::RunMode::Restream | crate::config::RunMode::Serve => {
+                self.ipc.discard_task().await;
             }
         }
         Ok(())
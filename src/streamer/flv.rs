@@ -1,45 +1,129 @@
+use super::flvparser::{FlvEvent, FlvItem, FlvParser, FlvRepairer, VideoCodec};
+use super::replay::{ReplayBuffer, TRACK_VIDEO};
 use crate::{
     config::{ConfigManager, Site},
+    dmlerr,
     dmlive::DMLMessage,
-    ipcmanager::IPCManager,
+    ipcmanager::{DMLStream, IPCManager},
+    streamfinder::StreamFinder,
 };
+use bytes::BytesMut;
 use log::{info, warn};
-use std::{cell::Cell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+};
 use tokio::io::AsyncWriteExt;
 
+const MAX_RECONNECT: usize = 3;
+const RENEW_AHEAD: i64 = 60; // in seconds
+const RENEW_MIN_ITVL: i64 = 10; // in seconds
+
+enum DownloadState {
+    Ended,
+    Stalled,
+    Failed,
+}
+
 #[allow(unused)]
 pub struct FLV {
-    url: String,
+    url: RefCell<String>,
+    backup_urls: RefCell<VecDeque<String>>,
+    video_size: Cell<(u64, u64)>,
+    video_codec: Cell<Option<VideoCodec>>,
+    renewed: Cell<bool>,
+    replay: Rc<ReplayBuffer>,
     ipc_manager: Rc<IPCManager>,
     cm: Rc<ConfigManager>,
     mtx: async_channel::Sender<DMLMessage>,
 }
 
 impl FLV {
-    pub fn new(url: String, cm: Rc<ConfigManager>, im: Rc<IPCManager>, mtx: async_channel::Sender<DMLMessage>) -> Self {
+    pub fn new(
+        mut urls: Vec<String>, replay: Rc<ReplayBuffer>, cm: Rc<ConfigManager>, im: Rc<IPCManager>,
+        mtx: async_channel::Sender<DMLMessage>,
+    ) -> Self {
+        let url = urls.remove(0);
         FLV {
-            url,
+            url: RefCell::new(url),
+            backup_urls: RefCell::new(urls.into()),
+            video_size: Cell::new((0, 0)),
+            video_codec: Cell::new(None),
+            renewed: Cell::new(false),
+            replay,
             ipc_manager: im,
             cm,
             mtx,
         }
     }
 
-    async fn download(&self) -> anyhow::Result<()> {
-        let mut stream = self.ipc_manager.get_video_socket().await?;
+    fn next_url(&self) -> bool {
+        let next = match self.backup_urls.borrow_mut().pop_front() {
+            Some(it) => it,
+            None => return false,
+        };
+        let old = self.url.replace(next);
+        self.backup_urls.borrow_mut().push_back(old);
+        info!("flv switch to backup url: {}", self.url.borrow());
+        true
+    }
+
+    async fn handle_event(&self, ev: FlvEvent) {
+        match ev {
+            FlvEvent::MetaData(j) => {
+                info!("flv metadata: {}", &j);
+                let w = j.pointer("/width").and_then(|it| it.as_f64()).unwrap_or(0.0) as u64;
+                let h = j.pointer("/height").and_then(|it| it.as_f64()).unwrap_or(0.0) as u64;
+                if w == 0 || h == 0 {
+                    return;
+                }
+                let old = self.video_size.replace((w, h));
+                if old.0 != 0 && old != (w, h) {
+                    info!("flv video size changed: {:?} -> {:?}", old, (w, h));
+                    let _ = self.mtx.send(DMLMessage::VideoParamsChanged((w, h))).await;
+                }
+            }
+            FlvEvent::VideoSequenceHeader { codec, changed } => {
+                if changed {
+                    info!("flv video sequence header changed, codec: {:?}", codec);
+                }
+                let old = self.video_codec.replace(Some(codec));
+                if old.is_some_and(|it| it != codec) {
+                    // the muxer can not switch codecs on the same track
+                    info!("flv video codec changed: {:?} -> {:?}", old, codec);
+                    let _ = self.mtx.send(DMLMessage::RequestRestart).await;
+                }
+            }
+            FlvEvent::AudioSequenceHeader { changed } => {
+                if changed {
+                    info!("flv audio sequence header changed");
+                }
+            }
+            FlvEvent::TimestampJump { from, to } => {
+                warn!("flv timestamp jump: {} -> {}", from, to);
+            }
+        }
+    }
+
+    async fn download(
+        &self, stream: &mut Box<dyn DMLStream>, repairer: &mut FlvRepairer, resume: bool,
+    ) -> anyhow::Result<DownloadState> {
         let client = reqwest::Client::builder()
             .user_agent(crate::utils::gen_ua())
             .connect_timeout(tokio::time::Duration::from_secs(10))
             .build()?;
-        let url = self.url.clone();
+        let url = self.url.borrow().clone();
         let room_url = self.cm.room_url.clone();
         let watch_dog = Cell::new(0);
+        let got_data = Cell::new(false);
         let watchdog_task = async {
             loop {
                 watch_dog.set(watch_dog.get() + 1);
                 tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
                 if watch_dog.get() > 10 {
                     warn!("connection too slow");
+                    self.cm.metrics.watchdog_trips.inc();
                     break;
                 }
             }
@@ -49,24 +133,167 @@ impl FLV {
             if self.cm.plive && matches!(self.cm.site, Site::BiliLive) {
                 resp = resp.header("Cookie", self.cm.bcookie.as_str());
             }
-            let mut resp = resp.send().await?;
-            while let Some(chunk) = resp.chunk().await? {
-                stream.write_all(&chunk).await?;
+            let mut resp = match resp.send().await.and_then(|it| it.error_for_status()) {
+                Ok(it) => it,
+                Err(e) => {
+                    info!("flv connect error: {}", e);
+                    return anyhow::Ok(());
+                }
+            };
+            let mut parser = FlvParser::new();
+            let mut out = BytesMut::with_capacity(64 * 1024);
+            let mut wait_keyframe = resume;
+            loop {
+                let chunk = match resp.chunk().await {
+                    Ok(Some(it)) => it,
+                    Ok(None) => break,
+                    Err(e) => {
+                        info!("flv download error: {}", e);
+                        break;
+                    }
+                };
                 watch_dog.set(0);
+                self.cm.metrics.bytes_downloaded.add(chunk.len() as u64);
+                parser.push(&chunk);
+                while let Some(item) = parser.next_item()? {
+                    match item {
+                        FlvItem::Header(it) => {
+                            if !resume {
+                                out.extend_from_slice(&it);
+                                self.replay.set_header(TRACK_VIDEO, &it);
+                            }
+                        }
+                        FlvItem::Tag(mut tag) => {
+                            let known_header = tag.is_sequence_header() && repairer.is_known_header(&tag);
+                            let dup_header = resume && known_header;
+                            for ev in repairer.process(&mut tag) {
+                                self.handle_event(ev).await;
+                            }
+                            if resume {
+                                // the decoder has seen these on the first connection
+                                if tag.is_script() || dup_header {
+                                    continue;
+                                }
+                                if wait_keyframe && tag.is_video() && !tag.is_sequence_header() {
+                                    if !tag.is_keyframe() {
+                                        continue;
+                                    }
+                                    wait_keyframe = false;
+                                }
+                            }
+                            let start = out.len();
+                            tag.encode(&mut out);
+                            if tag.is_script() || tag.is_sequence_header() {
+                                let reset = tag.is_sequence_header() && !known_header;
+                                self.replay.update_header(TRACK_VIDEO, tag.tag_type, &out[start..], reset).await;
+                            } else {
+                                let keyframe = tag.is_video() && tag.is_keyframe();
+                                self.replay.push(TRACK_VIDEO, &out[start..], keyframe).await;
+                            }
+                        }
+                    }
+                }
+                if !out.is_empty() {
+                    got_data.set(true);
+                    stream.write_all(&out).await?;
+                    out.clear();
+                }
+                if self.renewed.replace(false) {
+                    // everything written so far ends on a tag boundary
+                    info!("flv hand over to the renewed url");
+                    break;
+                }
             }
             info!("flv downloader exit normally");
             anyhow::Ok(())
         };
-        tokio::select! {
-            it = dl_task => { it?; }
-            _ = watchdog_task => {}
+        let stalled = tokio::select! {
+            it = dl_task => { it?; false }
+            _ = watchdog_task => { true }
+        };
+        Ok(match (got_data.get(), stalled) {
+            (false, _) => DownloadState::Failed,
+            (true, true) => DownloadState::Stalled,
+            (true, false) => DownloadState::Ended,
+        })
+    }
+
+    async fn refresh_url(&self) -> anyhow::Result<()> {
+        let sf = StreamFinder::new(self.cm.clone(), self.ipc_manager.clone(), self.mtx.clone());
+        let (_, urls) = sf.run().await?;
+        let mut urls: VecDeque<String> = urls.into();
+        let url = urls.pop_front().ok_or_else(|| dmlerr!())?;
+        if !url.contains(".flv") {
+            return Err(anyhow::anyhow!("stream is no longer flv"));
         }
+        info!("flv url refreshed: {}", &url);
+        *self.url.borrow_mut() = url;
+        *self.backup_urls.borrow_mut() = urls;
         Ok(())
     }
 
+    async fn renew_task(&self) {
+        loop {
+            let expire = crate::utils::url_expire_time(&self.url.borrow());
+            let expire = match expire {
+                Some(it) => it,
+                None => return std::future::pending().await,
+            };
+            let wait = (expire - RENEW_AHEAD - chrono::Utc::now().timestamp()).max(RENEW_MIN_ITVL);
+            tokio::time::sleep(tokio::time::Duration::from_secs(wait as u64)).await;
+            info!("flv url expires at {}, renewing", expire);
+            match self.refresh_url().await {
+                Ok(_) => self.renewed.set(true),
+                Err(e) => info!("flv renew url error: {}", e),
+            }
+        }
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
-        self.download().await?;
+        tokio::select! {
+            it = self.stream_task() => { it?; },
+            _ = self.renew_task() => {},
+        }
         info!("flv streamer exit");
         Ok(())
     }
+
+    async fn stream_task(&self) -> anyhow::Result<()> {
+        let mut stream = self.ipc_manager.get_video_socket().await?;
+        let mut repairer = FlvRepairer::new();
+        let mut resume = false;
+        let mut fails = 0;
+        loop {
+            match self.download(&mut stream, &mut repairer, resume).await? {
+                DownloadState::Ended => {
+                    resume = true;
+                    fails = 0;
+                }
+                DownloadState::Stalled => {
+                    resume = true;
+                    fails = 0;
+                    // a slow cdn rarely recovers, try the next one
+                    self.next_url();
+                }
+                DownloadState::Failed => {
+                    fails += 1;
+                    let backups = self.backup_urls.borrow().len();
+                    if fails > MAX_RECONNECT + backups {
+                        break;
+                    }
+                    if fails <= backups {
+                        self.next_url();
+                    } else if !resume {
+                        break;
+                    } else if self.refresh_url().await.map_err(|e| info!("flv refresh url error: {}", e)).is_err() {
+                        // the signed url may have expired
+                        break;
+                    }
+                }
+            }
+            info!("flv reconnecting...");
+            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        }
+        Ok(())
+    }
 }
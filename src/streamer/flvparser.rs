@@ -0,0 +1,522 @@
+use crate::dmlerr;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde_json::{Map, Value};
+
+const TAG_AUDIO: u8 = 8;
+const TAG_VIDEO: u8 = 9;
+const TAG_SCRIPT: u8 = 18;
+const TS_JUMP_THRESHOLD: i64 = 3000; // in ms
+const TS_BACKWARD_TOLERANCE: i64 = 1000; // in ms, audio and video may interleave a little
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    Avc,
+    Hevc,
+    Av1,
+    Other(u32),
+}
+
+#[derive(Debug)]
+pub enum FlvEvent {
+    MetaData(Value),
+    VideoSequenceHeader { codec: VideoCodec, changed: bool },
+    AudioSequenceHeader { changed: bool },
+    TimestampJump { from: i64, to: i64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct FlvTag {
+    pub tag_type: u8,
+    pub timestamp: u32,
+    pub stream_id: u32,
+    pub data: Bytes,
+}
+
+impl FlvTag {
+    pub fn is_video(&self) -> bool {
+        self.tag_type == TAG_VIDEO
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.tag_type == TAG_AUDIO
+    }
+
+    pub fn is_script(&self) -> bool {
+        self.tag_type == TAG_SCRIPT
+    }
+
+    pub fn video_codec(&self) -> Option<VideoCodec> {
+        if !self.is_video() || self.data.is_empty() {
+            return None;
+        }
+        let b = self.data[0];
+        if b & 0x80 != 0 {
+            // enhanced rtmp, fourcc follows the first byte
+            let fourcc = self.data.get(1..5)?;
+            return Some(match fourcc {
+                b"avc1" => VideoCodec::Avc,
+                b"hvc1" => VideoCodec::Hevc,
+                b"av01" => VideoCodec::Av1,
+                _ => VideoCodec::Other(u32::from_be_bytes(fourcc.try_into().ok()?)),
+            });
+        }
+        Some(match b & 0x0f {
+            7 => VideoCodec::Avc,
+            12 => VideoCodec::Hevc,
+            13 => VideoCodec::Av1,
+            it => VideoCodec::Other(it as u32),
+        })
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.is_video() && !self.data.is_empty() && (self.data[0] >> 4) & 0x07 == 1
+    }
+
+    pub fn is_sequence_header(&self) -> bool {
+        if self.data.len() < 2 {
+            return false;
+        }
+        if self.is_video() {
+            if self.data[0] & 0x80 != 0 {
+                self.data[0] & 0x0f == 0
+            } else {
+                self.data[1] == 0
+            }
+        } else if self.is_audio() {
+            // only aac has a sequence header
+            self.data[0] >> 4 == 10 && self.data[1] == 0
+        } else {
+            false
+        }
+    }
+
+    pub fn encode(&self, out: &mut BytesMut) {
+        let len = self.data.len() as u32;
+        out.put_u8(self.tag_type);
+        out.put_slice(&len.to_be_bytes()[1..]);
+        out.put_slice(&self.timestamp.to_be_bytes()[1..]);
+        out.put_u8((self.timestamp >> 24) as u8);
+        out.put_slice(&self.stream_id.to_be_bytes()[1..]);
+        out.put_slice(&self.data);
+        out.put_u32(len + 11);
+    }
+}
+
+pub enum FlvItem {
+    Header(Bytes),
+    Tag(FlvTag),
+}
+
+pub struct FlvParser {
+    buf: BytesMut,
+    header_done: bool,
+}
+
+impl FlvParser {
+    pub fn new() -> Self {
+        FlvParser {
+            buf: BytesMut::with_capacity(64 * 1024),
+            header_done: false,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    pub fn next_item(&mut self) -> anyhow::Result<Option<FlvItem>> {
+        if !self.header_done {
+            if self.buf.len() < 9 {
+                return Ok(None);
+            }
+            if &self.buf[0..3] != b"FLV" {
+                return Err(anyhow::anyhow!("not a flv stream"));
+            }
+            let offset = u32::from_be_bytes(self.buf[5..9].try_into()?) as usize;
+            // header plus PreviousTagSize0
+            if self.buf.len() < offset + 4 {
+                return Ok(None);
+            }
+            self.header_done = true;
+            return Ok(Some(FlvItem::Header(
+                self.buf.split_to(offset + 4).freeze(),
+            )));
+        }
+        if self.buf.len() < 11 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, self.buf[1], self.buf[2], self.buf[3]]) as usize;
+        if self.buf.len() < 11 + len + 4 {
+            return Ok(None);
+        }
+        let mut tag = self.buf.split_to(11 + len + 4);
+        let tag_type = tag.get_u8() & 0x1f;
+        tag.advance(3);
+        let ts = [tag[0], tag[1], tag[2]];
+        let timestamp = u32::from_be_bytes([tag[3], ts[0], ts[1], ts[2]]);
+        tag.advance(4);
+        let stream_id = u32::from_be_bytes([0, tag[0], tag[1], tag[2]]);
+        tag.advance(3);
+        let data = tag.split_to(len).freeze();
+        let prev_size = tag.get_u32() as usize;
+        if prev_size != len + 11 {
+            return Err(anyhow::anyhow!(
+                "flv tag size mismatch: {} != {}",
+                prev_size,
+                len + 11
+            ));
+        }
+        Ok(Some(FlvItem::Tag(FlvTag {
+            tag_type,
+            timestamp,
+            stream_id,
+            data,
+        })))
+    }
+}
+
+pub struct FlvRepairer {
+    ts_offset: i64,
+    last_ts: [Option<i64>; 2],
+    max_ts: i64,
+    video_header: Option<Bytes>,
+    audio_header: Option<Bytes>,
+}
+
+impl FlvRepairer {
+    pub fn new() -> Self {
+        FlvRepairer {
+            ts_offset: 0,
+            last_ts: [None, None],
+            max_ts: 0,
+            video_header: None,
+            audio_header: None,
+        }
+    }
+
+    /// Inspects a tag and rebases its timestamp, returning what changed in the stream.
+    pub fn process(&mut self, tag: &mut FlvTag) -> Vec<FlvEvent> {
+        let mut events = Vec::new();
+        if tag.is_script() {
+            if let Ok(it) = decode_script_tag(&tag.data) {
+                events.push(FlvEvent::MetaData(it));
+            }
+        } else if tag.is_sequence_header() {
+            if tag.is_video() {
+                let changed = self.video_header.as_ref().is_some_and(|it| it != &tag.data);
+                self.video_header = Some(tag.data.clone());
+                events.push(FlvEvent::VideoSequenceHeader {
+                    codec: tag.video_codec().unwrap_or(VideoCodec::Other(0)),
+                    changed,
+                });
+            } else {
+                let changed = self.audio_header.as_ref().is_some_and(|it| it != &tag.data);
+                self.audio_header = Some(tag.data.clone());
+                events.push(FlvEvent::AudioSequenceHeader { changed });
+            }
+        }
+        let idx = if tag.is_video() {
+            0
+        } else if tag.is_audio() {
+            1
+        } else {
+            return events;
+        };
+        let ts = tag.timestamp as i64;
+        match self.last_ts[idx] {
+            Some(last) => {
+                let diff = ts + self.ts_offset - last;
+                if !(-TS_BACKWARD_TOLERANCE..=TS_JUMP_THRESHOLD).contains(&diff) {
+                    events.push(FlvEvent::TimestampJump {
+                        from: last,
+                        to: ts + self.ts_offset,
+                    });
+                    self.ts_offset = self.max_ts - ts;
+                }
+            }
+            None => {
+                if self.last_ts.iter().all(|it| it.is_none()) {
+                    self.ts_offset = -ts;
+                }
+            }
+        }
+        let out = (ts + self.ts_offset).max(0);
+        self.last_ts[idx] = Some(out);
+        self.max_ts = self.max_ts.max(out);
+        tag.timestamp = out as u32;
+        events
+    }
+
+    pub fn is_known_header(&self, tag: &FlvTag) -> bool {
+        if tag.is_video() {
+            self.video_header.as_ref().is_some_and(|it| it == &tag.data)
+        } else if tag.is_audio() {
+            self.audio_header.as_ref().is_some_and(|it| it == &tag.data)
+        } else {
+            false
+        }
+    }
+}
+
+fn decode_script_tag(data: &[u8]) -> anyhow::Result<Value> {
+    let mut buf = data;
+    let name = decode_amf0(&mut buf)?;
+    if name.as_str() != Some("onMetaData") {
+        return Err(anyhow::anyhow!("unknown script tag: {}", name));
+    }
+    decode_amf0(&mut buf)
+}
+
+fn decode_amf0_string(buf: &mut &[u8], long: bool) -> anyhow::Result<String> {
+    let len = if long {
+        (buf.remaining() >= 4).then(|| buf.get_u32()).ok_or_else(|| dmlerr!())? as usize
+    } else {
+        (buf.remaining() >= 2).then(|| buf.get_u16()).ok_or_else(|| dmlerr!())? as usize
+    };
+    if buf.remaining() < len {
+        return Err(dmlerr!());
+    }
+    let s = String::from_utf8_lossy(&buf[..len]).to_string();
+    buf.advance(len);
+    Ok(s)
+}
+
+fn decode_amf0_object(buf: &mut &[u8]) -> anyhow::Result<Value> {
+    let mut ret = Map::new();
+    loop {
+        let k = decode_amf0_string(buf, false)?;
+        if k.is_empty() && buf.first() == Some(&9) {
+            buf.advance(1);
+            break;
+        }
+        let v = decode_amf0(buf)?;
+        ret.insert(k, v);
+    }
+    Ok(Value::Object(ret))
+}
+
+fn decode_amf0(buf: &mut &[u8]) -> anyhow::Result<Value> {
+    if buf.is_empty() {
+        return Err(dmlerr!());
+    }
+    let ret = match buf.get_u8() {
+        0 => {
+            if buf.remaining() < 8 {
+                return Err(dmlerr!());
+            }
+            serde_json::Number::from_f64(buf.get_f64()).map(Value::Number).unwrap_or(Value::Null)
+        }
+        1 => {
+            if buf.is_empty() {
+                return Err(dmlerr!());
+            }
+            Value::Bool(buf.get_u8() != 0)
+        }
+        2 => Value::String(decode_amf0_string(buf, false)?),
+        3 => decode_amf0_object(buf)?,
+        5 | 6 => Value::Null,
+        8 => {
+            if buf.remaining() < 4 {
+                return Err(dmlerr!());
+            }
+            buf.advance(4);
+            decode_amf0_object(buf)?
+        }
+        10 => {
+            if buf.remaining() < 4 {
+                return Err(dmlerr!());
+            }
+            let n = buf.get_u32();
+            let mut arr = Vec::new();
+            for _ in 0..n {
+                arr.push(decode_amf0(buf)?);
+            }
+            Value::Array(arr)
+        }
+        11 => {
+            if buf.remaining() < 10 {
+                return Err(dmlerr!());
+            }
+            let ms = buf.get_f64();
+            buf.advance(2);
+            serde_json::Number::from_f64(ms).map(Value::Number).unwrap_or(Value::Null)
+        }
+        12 => Value::String(decode_amf0_string(buf, true)?),
+        it => return Err(anyhow::anyhow!("unsupported amf0 type: {}", it)),
+    };
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag_type: u8, timestamp: u32, data: &[u8]) -> FlvTag {
+        FlvTag {
+            tag_type,
+            timestamp,
+            stream_id: 0,
+            data: Bytes::copy_from_slice(data),
+        }
+    }
+
+    fn amf0_string(s: &str) -> Vec<u8> {
+        let mut ret = (s.len() as u16).to_be_bytes().to_vec();
+        ret.extend_from_slice(s.as_bytes());
+        ret
+    }
+
+    // onMetaData as an ecma array, the way obs and most cdns send it
+    fn metadata() -> Vec<u8> {
+        let mut ret = vec![2];
+        ret.extend(amf0_string("onMetaData"));
+        ret.push(8);
+        ret.extend(3u32.to_be_bytes());
+        ret.extend(amf0_string("width"));
+        ret.push(0);
+        ret.extend(1920f64.to_be_bytes());
+        ret.extend(amf0_string("stereo"));
+        ret.extend([1, 1]);
+        ret.extend(amf0_string("encoder"));
+        ret.push(2);
+        ret.extend(amf0_string("obs"));
+        ret.extend(amf0_string(""));
+        ret.push(9);
+        ret
+    }
+
+    // header, metadata, avc and aac sequence headers, a keyframe and an audio frame
+    fn fixture() -> (Vec<u8>, Vec<FlvTag>) {
+        let mut out = BytesMut::new();
+        out.put_slice(b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00");
+        let tags = vec![
+            tag(TAG_SCRIPT, 0, &metadata()),
+            tag(TAG_VIDEO, 0, &[0x17, 0, 0, 0, 0, 1, 0x64, 0, 0x28]),
+            tag(TAG_AUDIO, 0, &[0xaf, 0, 0x12, 0x10]),
+            tag(TAG_VIDEO, 40, &[0x17, 1, 0, 0, 0, 0xaa, 0xbb]),
+            tag(TAG_AUDIO, 0x01000023, &[0xaf, 1, 0xcc]),
+        ];
+        for it in tags.iter() {
+            it.encode(&mut out);
+        }
+        (out.to_vec(), tags)
+    }
+
+    #[test]
+    fn splits_tags_across_chunks() {
+        let (data, tags) = fixture();
+        let mut parser = FlvParser::new();
+        let mut items = Vec::new();
+        // one byte at a time, every tag is split somewhere
+        for b in data.iter() {
+            parser.push(&[*b]);
+            while let Some(it) = parser.next_item().unwrap() {
+                items.push(it);
+            }
+        }
+        assert_eq!(items.len(), tags.len() + 1);
+        match &items[0] {
+            FlvItem::Header(it) => assert_eq!(it.len(), 13),
+            FlvItem::Tag(_) => panic!("expected the header first"),
+        }
+        let mut out = BytesMut::new();
+        for (it, expected) in items[1..].iter().zip(tags.iter()) {
+            let FlvItem::Tag(it) = it else { panic!("expected a tag") };
+            assert_eq!(it.tag_type, expected.tag_type);
+            assert_eq!(it.timestamp, expected.timestamp);
+            assert_eq!(it.data, expected.data);
+            it.encode(&mut out);
+        }
+        assert_eq!(&out[..], &data[13..]);
+    }
+
+    #[test]
+    fn classifies_tags() {
+        let (_, tags) = fixture();
+        assert!(tags[0].is_script());
+        assert!(tags[1].is_sequence_header());
+        assert_eq!(tags[1].video_codec(), Some(VideoCodec::Avc));
+        assert!(tags[2].is_sequence_header());
+        assert!(tags[3].is_keyframe() && !tags[3].is_sequence_header());
+        assert!(!tags[4].is_sequence_header());
+    }
+
+    #[test]
+    fn rejects_a_bad_stream() {
+        let mut parser = FlvParser::new();
+        parser.push(b"MP4\x01\x05\x00\x00\x00\x09");
+        assert!(parser.next_item().is_err());
+
+        let (mut data, _) = fixture();
+        // the PreviousTagSize of the metadata tag
+        let end = 13 + 11 + metadata().len() + 3;
+        data[end] ^= 0xff;
+        let mut parser = FlvParser::new();
+        parser.push(&data);
+        assert!(matches!(parser.next_item(), Ok(Some(FlvItem::Header(_)))));
+        assert!(parser.next_item().is_err());
+    }
+
+    #[test]
+    fn decodes_metadata() {
+        let mut repairer = FlvRepairer::new();
+        let events = repairer.process(&mut tag(TAG_SCRIPT, 0, &metadata()));
+        let Some(FlvEvent::MetaData(it)) = events.first() else {
+            panic!("expected metadata")
+        };
+        assert_eq!(it["width"], 1920.0);
+        assert_eq!(it["stereo"], true);
+        assert_eq!(it["encoder"], "obs");
+        // a truncated object is an error, not a panic
+        let data = metadata();
+        assert!(decode_script_tag(&data[..data.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn reports_changed_sequence_headers() {
+        let mut repairer = FlvRepairer::new();
+        let avc = tag(TAG_VIDEO, 0, &[0x17, 0, 0, 0, 0, 1, 0x64, 0, 0x28]);
+        let events = repairer.process(&mut avc.clone());
+        assert!(matches!(
+            events[0],
+            FlvEvent::VideoSequenceHeader { changed: false, .. }
+        ));
+        assert!(repairer.is_known_header(&avc));
+        let events = repairer.process(&mut tag(
+            TAG_VIDEO,
+            0,
+            &[0x17, 0, 0, 0, 0, 1, 0x4d, 0, 0x1f],
+        ));
+        assert!(matches!(
+            events[0],
+            FlvEvent::VideoSequenceHeader { changed: true, .. }
+        ));
+        assert!(!repairer.is_known_header(&avc));
+    }
+
+    #[test]
+    fn rebases_timestamps() {
+        let mut repairer = FlvRepairer::new();
+        let mut process = |tag_type, ts| {
+            let mut it = tag(tag_type, ts, &[0x27, 1]);
+            let events = repairer.process(&mut it);
+            (it.timestamp, events)
+        };
+        // the first tag starts at 0
+        assert_eq!(process(TAG_VIDEO, 5000).0, 0);
+        assert_eq!(process(TAG_AUDIO, 5010).0, 10);
+        assert_eq!(process(TAG_VIDEO, 5040).0, 40);
+        // audio a little behind video is fine
+        let (ts, events) = process(TAG_AUDIO, 4500);
+        assert!(events.is_empty());
+        assert_eq!(ts, 0);
+        // a jump continues from where the stream was
+        let (ts, events) = process(TAG_VIDEO, 900000);
+        assert!(matches!(
+            events[0],
+            FlvEvent::TimestampJump { from: 40, .. }
+        ));
+        assert_eq!(ts, 40);
+        assert_eq!(process(TAG_VIDEO, 900040).0, 80);
+    }
+}
@@ -1,6 +1,12 @@
+use super::replay::{ReplayBuffer, TRACK_VIDEO};
 use super::segment::MediaSegment;
-use crate::{config::ConfigManager, dmlive::DMLMessage, ipcmanager::IPCManager, streamer::segment::SegmentStream};
-use log::info;
+use crate::{
+    config::ConfigManager, dmlive::DMLMessage, ipcmanager::IPCManager, streamer::segment::SegmentStream,
+    streamfinder::StreamFinder,
+};
+use bytes::Bytes;
+use futures::{stream::FuturesOrdered, StreamExt};
+use log::{info, warn};
 use reqwest::Client;
 use std::{
     cell::{Cell, RefCell},
@@ -10,6 +16,11 @@ use std::{
 };
 use tokio::io::AsyncWriteExt;
 
+const MAX_PREFETCH: usize = 3;
+const MAX_RETRY: u32 = 3;
+const RENEW_AHEAD: i64 = 60; // in seconds
+const RENEW_MIN_ITVL: i64 = 10; // in seconds
+
 #[allow(unused)]
 #[derive(Debug)]
 pub struct M3U8 {
@@ -21,28 +32,54 @@ pub struct M3U8 {
 
 #[allow(unused)]
 pub struct HLS {
-    url: String,
+    url: RefCell<String>,
+    backup_urls: RefCell<VecDeque<String>>,
     header: RefCell<Vec<u8>>,
     header_done: Cell<bool>,
+    replay: Rc<ReplayBuffer>,
     ipc_manager: Rc<IPCManager>,
     cm: Rc<ConfigManager>,
     mtx: async_channel::Sender<DMLMessage>,
     watch_dog: Cell<bool>,
+    switches: Cell<usize>, // backup urls tried since the stream last delivered
 }
 
 impl HLS {
-    pub fn new(url: String, cm: Rc<ConfigManager>, im: Rc<IPCManager>, mtx: async_channel::Sender<DMLMessage>) -> Self {
+    pub fn new(
+        mut urls: Vec<String>, replay: Rc<ReplayBuffer>, cm: Rc<ConfigManager>, im: Rc<IPCManager>,
+        mtx: async_channel::Sender<DMLMessage>,
+    ) -> Self {
+        let url = urls.remove(0);
         HLS {
-            url,
+            url: RefCell::new(url),
+            backup_urls: RefCell::new(urls.into()),
+            replay,
             ipc_manager: im,
             cm,
             mtx,
             header: RefCell::new(Vec::new()),
             watch_dog: Cell::new(false),
+            switches: Cell::new(0),
             header_done: Cell::new(false),
         }
     }
 
+    // one pass over the candidates, after that the stream has to be resolved again
+    fn next_url(&self) -> bool {
+        if self.switches.get() >= self.backup_urls.borrow().len() {
+            return false;
+        }
+        self.switches.set(self.switches.get() + 1);
+        let next = match self.backup_urls.borrow_mut().pop_front() {
+            Some(it) => it,
+            None => return false,
+        };
+        let old = self.url.replace(next);
+        self.backup_urls.borrow_mut().push_back(old);
+        info!("hls: switch to backup url: {}", self.url.borrow());
+        true
+    }
+
     pub fn decode_m3u8(m3u8_text: &str) -> anyhow::Result<M3U8> {
         let mut lines = m3u8_text.lines();
         let mut sq = 0u64;
@@ -112,7 +149,7 @@ impl HLS {
         let url = if clip.starts_with("http") {
             clip.to_string()
         } else {
-            let url = url::Url::parse(&self.url)?;
+            let url = url::Url::parse(&self.url.borrow())?;
             let url2 = url.join(&clip)?;
             if url2.as_str().contains("?") {
                 url2.as_str().to_string()
@@ -123,51 +160,124 @@ impl HLS {
         Ok(url)
     }
 
+    async fn fetch_clip(&self, client: &Client, clip: MediaSegment) -> (MediaSegment, anyhow::Result<Bytes>) {
+        let data = self.fetch_clip_with_retry(client, &clip).await;
+        (clip, data)
+    }
+
+    async fn fetch_clip_with_retry(&self, client: &Client, clip: &MediaSegment) -> anyhow::Result<Bytes> {
+        let url = self.parse_clip_url(&clip.url)?;
+        let mut retry = 0;
+        loop {
+            let start = std::time::Instant::now();
+            let resp =
+                client.get(&url).header("Connection", "keep-alive").send().await.and_then(|it| it.error_for_status());
+            let e = match resp {
+                Ok(it) => match it.bytes().await {
+                    Ok(it) => {
+                        self.cm.metrics.segment_fetch.observe(start.elapsed().as_secs_f64());
+                        self.cm.metrics.bytes_downloaded.add(it.len() as u64);
+                        return Ok(it);
+                    }
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+            if retry >= MAX_RETRY {
+                return Err(e.into());
+            }
+            retry += 1;
+            info!("hls: fetch clip failed, retry {}: {}", retry, e);
+            tokio::time::sleep(tokio::time::Duration::from_millis(500 << retry)).await;
+        }
+    }
+
     async fn download_task(&self, client: &Client, ss: &SegmentStream) -> anyhow::Result<()> {
         let mut stream = self.ipc_manager.get_video_socket().await?;
-        let mut rx = ss.clip_rx.borrow_mut();
-        while let Some(mut clip) = rx.recv().await {
-            // info!("hls: clip: {}", &clip);
-            if self.header_done.get().not() && clip.is_header {
-                clip.skip = 0;
-                self.header_done.set(true);
-            } else if clip.skip == 2 {
-                continue;
-            }
-            let url = self.parse_clip_url(&clip.url)?;
-            let mut resp = client.get(url).header("Connection", "keep-alive").send().await?;
-            while let Some(chunk) = resp.chunk().await? {
-                if clip.skip == 0 {
-                    stream.write_all(&chunk).await?;
-                }
+        let mut rx = ss.take_clip_rx()?;
+        let mut pending = FuturesOrdered::new();
+        let mut rx_closed = false;
+        loop {
+            tokio::select! {
+                Some(it) = pending.next(), if pending.is_empty().not() => {
+                    let (clip, data): (MediaSegment, anyhow::Result<Bytes>) = it;
+                    match data {
+                        Ok(data) => {
+                            if clip.skip == 0 {
+                                stream.write_all(&data).await?;
+                                if clip.is_header {
+                                    self.replay.set_header(TRACK_VIDEO, &data);
+                                } else {
+                                    self.replay.push(TRACK_VIDEO, &data, true).await;
+                                }
+                            }
+                            self.watch_dog.set(true);
+                            self.switches.set(0);
+                        }
+                        Err(e) if clip.is_header && clip.skip == 0 => {
+                            self.cm.metrics.segment_failures.inc();
+                            return Err(e);
+                        }
+                        Err(e) => {
+                            self.cm.metrics.segment_failures.inc();
+                            warn!("hls: skip clip {}: {}", &clip.url, e);
+                        }
+                    }
+                },
+                clip = rx.recv(), if rx_closed.not() && pending.len() < MAX_PREFETCH => {
+                    let mut clip = match clip {
+                        Some(it) => it,
+                        None => {
+                            rx_closed = true;
+                            continue;
+                        }
+                    };
+                    if self.header_done.get().not() && clip.is_header {
+                        clip.skip = 0;
+                        self.header_done.set(true);
+                    } else if clip.skip == 2 {
+                        continue;
+                    }
+                    pending.push_back(self.fetch_clip(client, clip));
+                },
+                else => { break; }
             }
-            self.watch_dog.set(true);
         }
         Ok(())
     }
 
     async fn refresh_m3u8_task(&self, client: &Client, ss: &SegmentStream) -> anyhow::Result<()> {
-        let mut rx = ss.refresh_rx.borrow_mut();
+        let mut rx = ss.take_refresh_rx()?;
+        let mut fails = 0;
         while let Some(_) = rx.recv().await {
+            let url = self.url.borrow().clone();
             let resp = client
-                .get(&self.url)
+                .get(&url)
                 .timeout(tokio::time::Duration::from_millis(ss.refresh_itvl.get()))
                 .header("Connection", "keep-alive")
                 // maybe bypass twitch ad
                 // .header("X-Forwarded-For", "::1")
                 .send()
-                .await;
-            let resp = match resp {
-                Ok(it) => it,
-                Err(e) => {
-                    info!("{}", e);
-                    continue;
-                }
+                .await
+                .and_then(|it| it.error_for_status());
+            let m3u8_text = match resp {
+                Ok(it) => it.text().await,
+                Err(e) => Err(e),
             };
-            let m3u8_text = match resp.text().await {
-                Ok(it) => it,
+            let m3u8_text = match m3u8_text {
+                Ok(it) => {
+                    fails = 0;
+                    it
+                }
                 Err(e) => {
                     info!("{}", e);
+                    fails += 1;
+                    if fails > MAX_RETRY {
+                        if self.next_url().not() {
+                            return Err(anyhow::anyhow!("hls: playlist unavailable"));
+                        }
+                        fails = 0;
+                    }
                     continue;
                 }
             };
@@ -192,25 +302,208 @@ impl HLS {
             }
             if cnt > max_waiting {
                 info!("watch dog failed!");
-                return Err(anyhow::anyhow!("watch dog failed!"));
+                self.cm.metrics.watchdog_trips.inc();
+                if self.next_url().not() {
+                    return Err(anyhow::anyhow!("watch dog failed!"));
+                }
+                cnt = 0;
             }
             self.watch_dog.set(false);
         }
     }
 
+    async fn renew_url(&self) -> anyhow::Result<()> {
+        let sf = StreamFinder::new(self.cm.clone(), self.ipc_manager.clone(), self.mtx.clone());
+        let (_, urls) = sf.run().await?;
+        let mut urls: VecDeque<String> = urls.into();
+        let url = urls.pop_front().ok_or_else(|| crate::dmlerr!())?;
+        if url.contains(".m3u8").not() {
+            return Err(anyhow::anyhow!("stream is no longer hls"));
+        }
+        info!("hls: url renewed: {}", &url);
+        // the next playlist refresh picks it up, clips in flight keep their old urls
+        *self.url.borrow_mut() = url;
+        *self.backup_urls.borrow_mut() = urls;
+        self.switches.set(0);
+        Ok(())
+    }
+
+    async fn renew_task(&self) -> anyhow::Result<()> {
+        loop {
+            let expire = crate::utils::url_expire_time(&self.url.borrow());
+            let expire = match expire {
+                Some(it) => it,
+                None => return std::future::pending().await,
+            };
+            let wait = (expire - RENEW_AHEAD - chrono::Utc::now().timestamp()).max(RENEW_MIN_ITVL);
+            tokio::time::sleep(tokio::time::Duration::from_secs(wait as u64)).await;
+            info!("hls: url expires at {}, renewing", expire);
+            if let Err(e) = self.renew_url().await {
+                info!("hls: renew url error: {}", e);
+            }
+        }
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
         let client = reqwest::Client::builder()
             .user_agent(crate::utils::gen_ua())
             .timeout(tokio::time::Duration::from_secs(30))
             .build()?;
-        let seg_stream = SegmentStream::new();
+        let seg_stream = SegmentStream::new(self.cm.from_start);
         tokio::select! {
             it = self.refresh_m3u8_task(&client, &seg_stream) => { it?; },
             it = self.download_task(&client, &seg_stream) => { it?; },
             it = self.watch_dog_task() => { it?; },
+            it = self.renew_task() => { it?; },
             it = seg_stream.run() => { it?; },
         }
         info!("hls streamer exit");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    // a segment server where "slow" answers late, "flaky" fails twice and "bad" always fails
+    async fn stub_server(listener: TcpListener, served: &RefCell<Vec<String>>, hits: &RefCell<HashMap<String, u32>>) {
+        let mut tasks = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(_) = tasks.next() => {},
+                it = listener.accept() => {
+                    let (mut s, _) = it.unwrap();
+                    tasks.push(async move {
+                        let mut buf = Vec::new();
+                        let mut chunk = [0u8; 1024];
+                        while !buf.windows(4).any(|it| it == b"\r\n\r\n") {
+                            let n = s.read(&mut chunk).await.unwrap();
+                            if n == 0 {
+                                return;
+                            }
+                            buf.extend_from_slice(&chunk[..n]);
+                        }
+                        let head = String::from_utf8_lossy(&buf).to_string();
+                        let path = head.split(' ').nth(1).unwrap_or("").split('?').next().unwrap().to_string();
+                        let name = path.trim_start_matches('/').trim_end_matches(".ts").to_string();
+                        let hit = {
+                            let mut hits = hits.borrow_mut();
+                            let it = hits.entry(name.clone()).or_insert(0);
+                            *it += 1;
+                            *it
+                        };
+                        let ok = match name.as_str() {
+                            "slow" => {
+                                tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+                                true
+                            }
+                            "flaky" => hit > 2,
+                            "bad" => false,
+                            _ => true,
+                        };
+                        let resp = if ok {
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                name.len(),
+                                name
+                            )
+                        } else {
+                            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                                .to_string()
+                        };
+                        s.write_all(resp.as_bytes()).await.unwrap();
+                        served.borrow_mut().push(name);
+                    });
+                }
+            }
+        }
+    }
+
+    fn segment(name: &str) -> MediaSegment {
+        MediaSegment {
+            skip: 0,
+            is_header: false,
+            props: HashMap::new(),
+            url: format!("{}.ts", name),
+        }
+    }
+
+    struct Outcome {
+        out: Vec<u8>,               // what reached the video socket
+        served: Vec<String>,        // segments in the order the stub finished them
+        hits: HashMap<String, u32>, // requests per segment
+        cm: Rc<ConfigManager>,
+    }
+
+    // downloads `clips` from the stub until the video socket got `len` bytes
+    async fn run_download(clips: &[&str], len: usize) -> Outcome {
+        let config = std::env::temp_dir().join(format!("dmlive-test-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&config, "").unwrap();
+        let args = crate::Args::parse_from(["dmlive", "--tcp", "-r", "-u", "https://live.bilibili.com/1"]);
+        let cm = Rc::new(ConfigManager::new(&config, &args));
+        let _ = std::fs::remove_file(&config);
+        let mut im = IPCManager::new(cm.clone());
+        im.run().await.unwrap();
+        let im = Rc::new(im);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}/live.m3u8", listener.local_addr().unwrap());
+        let (mtx, _mrx) = async_channel::unbounded();
+        let replay = Rc::new(ReplayBuffer::new(cm.clone(), im.clone()));
+        let hls = HLS::new(vec![base], replay, cm.clone(), im.clone(), mtx);
+        let client = Client::new();
+        let ss = SegmentStream::new(true);
+        ss.update_sequence(1, clips.iter().map(|it| segment(it)).collect(), 1000).await.unwrap();
+        let served = RefCell::new(Vec::new());
+        let hits = RefCell::new(HashMap::new());
+        let reader = async {
+            let addr = im.get_video_socket_path().trim_start_matches("tcp://").to_string();
+            let mut s = TcpStream::connect(addr).await.unwrap();
+            let mut out = vec![0u8; len];
+            s.read_exact(&mut out).await.unwrap();
+            out
+        };
+        let out = tokio::select! {
+            _ = stub_server(listener, &served, &hits) => unreachable!(),
+            it = hls.download_task(&client, &ss) => panic!("download task ended: {:?}", it.err()),
+            it = reader => it,
+        };
+        Outcome {
+            out,
+            served: served.into_inner(),
+            hits: hits.into_inner(),
+            cm,
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_order_when_downloads_finish_out_of_order() {
+        let it = run_download(&["slow", "fast", "last"], 12).await;
+        assert_eq!(it.served.first().map(|it| it.as_str()), Some("fast"));
+        assert_eq!(it.out, b"slowfastlast");
+    }
+
+    #[tokio::test]
+    async fn retries_with_backoff() {
+        let start = std::time::Instant::now();
+        let it = run_download(&["flaky", "next"], 9).await;
+        assert_eq!(it.out, b"flakynext");
+        assert_eq!(it.hits.get("flaky"), Some(&3));
+        // 1s before the first retry and 2s before the second
+        assert!(start.elapsed() >= std::time::Duration::from_secs(3));
+        assert_eq!(it.cm.metrics.segment_failures.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn skips_a_segment_that_keeps_failing() {
+        let it = run_download(&["first", "bad", "after"], 10).await;
+        assert_eq!(it.out, b"firstafter");
+        assert_eq!(it.hits.get("bad"), Some(&(MAX_RETRY + 1)));
+        assert_eq!(it.cm.metrics.segment_failures.get(), 1);
+    }
+}
@@ -1,5 +1,8 @@
 pub mod flv;
+pub mod flvparser;
 pub mod hls;
+pub mod mpd;
+pub mod replay;
 pub mod segment;
 pub mod youtube;
 
@@ -8,9 +11,11 @@ use crate::{
     dmlive::DMLMessage,
     ipcmanager::IPCManager,
 };
+use replay::ReplayBuffer;
 use std::rc::Rc;
 
 pub struct Streamer {
+    pub replay: Rc<ReplayBuffer>,
     ipc_manager: Rc<IPCManager>,
     cm: Rc<ConfigManager>,
     mtx: async_channel::Sender<DMLMessage>,
@@ -19,6 +24,7 @@ pub struct Streamer {
 impl Streamer {
     pub fn new(cm: Rc<ConfigManager>, im: Rc<IPCManager>, mtx: async_channel::Sender<DMLMessage>) -> Self {
         Self {
+            replay: Rc::new(ReplayBuffer::new(cm.clone(), im.clone())),
             ipc_manager: im,
             cm,
             mtx,
@@ -26,10 +32,13 @@ impl Streamer {
     }
 
     pub async fn run(&self, rurl: &Vec<String>) -> anyhow::Result<()> {
+        // timestamps start over with every connection
+        self.replay.reset().await;
         match self.cm.stream_type.get() {
             StreamType::FLV => {
                 let s = flv::FLV::new(
-                    rurl[0].to_string(),
+                    rurl.clone(),
+                    self.replay.clone(),
                     self.cm.clone(),
                     self.ipc_manager.clone(),
                     self.mtx.clone(),
@@ -38,7 +47,8 @@ impl Streamer {
             }
             StreamType::HLS(_) => {
                 let s = hls::HLS::new(
-                    rurl[0].to_string(),
+                    rurl.clone(),
+                    self.replay.clone(),
                     self.cm.clone(),
                     self.ipc_manager.clone(),
                     self.mtx.clone(),
@@ -48,8 +58,7 @@ impl Streamer {
             StreamType::DASH => {
                 let s = youtube::Youtube::new(
                     rurl[0].to_string(),
-                    rurl[1].to_string(),
-                    rurl[2].parse().unwrap_or(0),
+                    self.replay.clone(),
                     self.cm.clone(),
                     self.ipc_manager.clone(),
                     self.mtx.clone(),
@@ -0,0 +1,405 @@
+use crate::{config::Quality, dmlerr};
+use chrono::{DateTime, Utc};
+use roxmltree::Node;
+
+#[derive(Debug, Clone, Default)]
+pub struct SegmentInfo {
+    pub media: Option<String>,
+    pub initialization: Option<String>,
+    pub start_number: u64,
+    pub timescale: u64,
+    pub duration: u64,
+    pub timeline: Vec<(u64, u64, i64)>, // t, d, r
+    pub media_urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Representation {
+    pub id: String,
+    pub mime_type: String,
+    pub codecs: String,
+    pub bandwidth: u64,
+    pub width: u64,
+    pub height: u64,
+    pub frame_rate: f64,
+    pub base_url: String,
+    pub segment: Option<SegmentInfo>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Default)]
+pub struct AdaptationSet {
+    pub mime_type: String,
+    pub representations: Vec<Representation>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Default)]
+pub struct Mpd {
+    pub is_live: bool,
+    pub availability_start_time: Option<DateTime<Utc>>,
+    pub minimum_update_period: Option<f64>,
+    pub adaptation_sets: Vec<AdaptationSet>,
+}
+
+fn parse_duration(s: &str) -> Option<f64> {
+    // only the PT..H..M..S form shows up in live manifests
+    let s = s.strip_prefix("PT")?;
+    let mut ret = 0.0;
+    let mut num = String::new();
+    for c in s.chars() {
+        match c {
+            'H' => ret += num.parse::<f64>().ok()? * 3600.0,
+            'M' => ret += num.parse::<f64>().ok()? * 60.0,
+            'S' => ret += num.parse::<f64>().ok()?,
+            _ => {
+                num.push(c);
+                continue;
+            }
+        }
+        num.clear();
+    }
+    Some(ret)
+}
+
+pub fn parse_frame_rate(s: &str) -> f64 {
+    match s.split_once('/') {
+        Some((a, b)) => a.parse::<f64>().unwrap_or(0.0) / b.parse::<f64>().unwrap_or(1.0),
+        None => s.parse().unwrap_or(0.0),
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.tag_name().name() == name)
+}
+
+fn join_base_url(base: &str, node: Node) -> String {
+    match child(node, "BaseURL").and_then(|it| it.text()) {
+        Some(it) => match url::Url::parse(base).and_then(|b| b.join(it.trim())) {
+            Ok(it) => it.to_string(),
+            Err(_) => it.trim().to_string(),
+        },
+        None => base.to_string(),
+    }
+}
+
+// `period_end` is the duration of the period in seconds, where an S repeating to the end stops
+fn parse_segment_info(node: Node, parent: Option<&SegmentInfo>, period_end: Option<f64>) -> Option<SegmentInfo> {
+    let seg = child(node, "SegmentTemplate").or_else(|| child(node, "SegmentList"));
+    let seg = match seg {
+        Some(it) => it,
+        None => return parent.cloned(),
+    };
+    let mut ret = parent.cloned().unwrap_or(SegmentInfo {
+        start_number: 1,
+        timescale: 1,
+        ..Default::default()
+    });
+    if let Some(it) = seg.attribute("media") {
+        ret.media = Some(it.to_string());
+    }
+    if let Some(it) = seg.attribute("initialization") {
+        ret.initialization = Some(it.to_string());
+    }
+    if let Some(it) = child(seg, "Initialization").and_then(|it| it.attribute("sourceURL")) {
+        ret.initialization = Some(it.to_string());
+    }
+    ret.start_number = seg.attribute("startNumber").and_then(|it| it.parse().ok()).unwrap_or(ret.start_number);
+    ret.timescale = seg.attribute("timescale").and_then(|it| it.parse().ok()).unwrap_or(ret.timescale);
+    ret.duration = seg.attribute("duration").and_then(|it| it.parse().ok()).unwrap_or(ret.duration);
+    if let Some(tl) = child(seg, "SegmentTimeline") {
+        ret.timeline.clear();
+        let pto: u64 = seg.attribute("presentationTimeOffset").and_then(|it| it.parse().ok()).unwrap_or(0);
+        let entries: Vec<Node> = tl.children().filter(|n| n.tag_name().name() == "S").collect();
+        // an S without t starts where the one before it ends
+        let mut next_t = pto;
+        for (i, s) in entries.iter().enumerate() {
+            let t: u64 = s.attribute("t").and_then(|it| it.parse().ok()).unwrap_or(next_t);
+            let d: u64 = s.attribute("d").and_then(|it| it.parse().ok()).unwrap_or(0);
+            let mut r: i64 = s.attribute("r").and_then(|it| it.parse().ok()).unwrap_or(0);
+            if r < 0 {
+                // repeats up to the next S, or the end of the period, counted as one if neither is known
+                let end = entries
+                    .get(i + 1)
+                    .and_then(|it| it.attribute("t"))
+                    .and_then(|it| it.parse::<u64>().ok())
+                    .or_else(|| period_end.map(|it| pto + (it * ret.timescale as f64) as u64));
+                r = match end {
+                    Some(end) if d > 0 && end > t => (end - t).div_ceil(d) as i64 - 1,
+                    _ => 0,
+                };
+            }
+            ret.timeline.push((t, d, r));
+            next_t = t + d * (r as u64 + 1);
+        }
+    }
+    let urls: Vec<String> = seg
+        .children()
+        .filter(|n| n.tag_name().name() == "SegmentURL")
+        .filter_map(|n| n.attribute("media").map(|it| it.to_string()))
+        .collect();
+    if !urls.is_empty() {
+        ret.media_urls = urls;
+    }
+    Some(ret)
+}
+
+impl SegmentInfo {
+    pub fn segment_duration_ms(&self) -> Option<u64> {
+        let d = match self.timeline.last() {
+            Some(it) => it.1,
+            None => self.duration,
+        };
+        if d == 0 || self.timescale == 0 {
+            return None;
+        }
+        Some(d * 1000 / self.timescale)
+    }
+
+    pub fn segment_count(&self) -> u64 {
+        if !self.media_urls.is_empty() {
+            return self.media_urls.len() as u64;
+        }
+        self.timeline.iter().map(|it| it.2.max(0) as u64 + 1).sum()
+    }
+}
+
+impl Representation {
+    pub fn is_video(&self) -> bool {
+        self.mime_type.contains("video")
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.mime_type.contains("audio")
+    }
+}
+
+impl Mpd {
+    pub fn parse(mpd_text: &str, mpd_url: &str) -> anyhow::Result<Mpd> {
+        let doc = roxmltree::Document::parse(mpd_text)?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "MPD" {
+            return Err(anyhow::anyhow!("not a mpd document"));
+        }
+        let mut ret = Mpd {
+            is_live: root.attribute("type") == Some("dynamic"),
+            availability_start_time: root
+                .attribute("availabilityStartTime")
+                .and_then(|it| DateTime::parse_from_rfc3339(it).ok())
+                .map(|it| it.with_timezone(&Utc)),
+            minimum_update_period: root.attribute("minimumUpdatePeriod").and_then(parse_duration),
+            adaptation_sets: Vec::new(),
+        };
+        let base = join_base_url(mpd_url, root);
+        let period = child(root, "Period").ok_or_else(|| dmlerr!())?;
+        let base = join_base_url(&base, period);
+        let period_end = period
+            .attribute("duration")
+            .or_else(|| root.attribute("mediaPresentationDuration"))
+            .and_then(parse_duration);
+        let period_seg = parse_segment_info(period, None, period_end);
+        for aset in period.children().filter(|n| n.tag_name().name() == "AdaptationSet") {
+            let aset_base = join_base_url(&base, aset);
+            let aset_seg = parse_segment_info(aset, period_seg.as_ref(), period_end);
+            let mime_type = aset.attribute("mimeType").unwrap_or("").to_string();
+            let mut reps = Vec::new();
+            for rep in aset.children().filter(|n| n.tag_name().name() == "Representation") {
+                let attr = |k: &str| rep.attribute(k).or_else(|| aset.attribute(k));
+                reps.push(Representation {
+                    id: attr("id").unwrap_or("").to_string(),
+                    mime_type: attr("mimeType").unwrap_or(&mime_type).to_string(),
+                    codecs: attr("codecs").unwrap_or("").to_string(),
+                    bandwidth: attr("bandwidth").and_then(|it| it.parse().ok()).unwrap_or(0),
+                    width: attr("width").and_then(|it| it.parse().ok()).unwrap_or(0),
+                    height: attr("height").and_then(|it| it.parse().ok()).unwrap_or(0),
+                    frame_rate: attr("frameRate").map(parse_frame_rate).unwrap_or(0.0),
+                    base_url: join_base_url(&aset_base, rep),
+                    segment: parse_segment_info(rep, aset_seg.as_ref(), period_end),
+                });
+            }
+            ret.adaptation_sets.push(AdaptationSet {
+                mime_type,
+                representations: reps,
+            });
+        }
+        Ok(ret)
+    }
+
+    pub fn representations(&self) -> impl Iterator<Item = &Representation> {
+        self.adaptation_sets.iter().flat_map(|it| it.representations.iter())
+    }
+
+    pub fn select_video(&self, quality: Quality, codec_rank: impl Fn(&str) -> usize) -> Option<&Representation> {
+        let rank = self.representations().filter(|it| it.is_video()).map(|it| codec_rank(&it.codecs)).min()?;
+        let mut reps: Vec<&Representation> =
+            self.representations().filter(|it| it.is_video() && codec_rank(&it.codecs) == rank).collect();
+        reps.sort_by_key(|it| std::cmp::Reverse(it.bandwidth));
+        quality.pick(&reps, |it| (it.height, it.frame_rate.round() as u64)).copied()
+    }
+
+    pub fn best_audio(&self) -> Option<&Representation> {
+        self.representations().filter(|it| it.is_audio()).max_by_key(|it| it.bandwidth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the shape youtube serves for a live stream
+    const LIVE_MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:DASH:schema:MPD:2011" type="dynamic" availabilityStartTime="2024-01-01T00:00:00Z" minimumUpdatePeriod="PT2.5S">
+  <BaseURL>https://example.com/live/</BaseURL>
+  <Period>
+    <AdaptationSet mimeType="audio/mp4">
+      <SegmentTemplate timescale="48000" media="audio/$Number$.m4s" initialization="audio/init.mp4" startNumber="10">
+        <SegmentTimeline><S t="0" d="96000" r="2"/><S d="95000"/></SegmentTimeline>
+      </SegmentTemplate>
+      <Representation id="140" codecs="mp4a.40.2" bandwidth="144000"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="video/mp4">
+      <SegmentTemplate timescale="90000" media="video/$RepresentationID$/$Number$.m4s" startNumber="10">
+        <SegmentTimeline><S t="0" d="180000" r="3"/></SegmentTimeline>
+      </SegmentTemplate>
+      <Representation id="137" codecs="avc1.640028" bandwidth="4000000" width="1920" height="1080" frameRate="30"/>
+      <Representation id="136" codecs="avc1.4d401f" bandwidth="2000000" width="1280" height="720" frameRate="30"/>
+      <Representation id="299" codecs="avc1.64002a" bandwidth="6000000" width="1920" height="1080" frameRate="60000/1001"/>
+      <Representation id="303" codecs="vp9" bandwidth="5000000" width="1920" height="1080" frameRate="60">
+        <BaseURL>vp9/</BaseURL>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    // a finished video listing its segments, attributes set on the adaptation set
+    const STATIC_MPD: &str = r#"<MPD type="static" mediaPresentationDuration="PT1M">
+  <Period>
+    <BaseURL>https://cdn.example.com/v/</BaseURL>
+    <AdaptationSet mimeType="video/mp4" codecs="hev1.1.6.L120.90" width="1280" height="720">
+      <Representation id="64" bandwidth="1000000">
+        <SegmentList timescale="1000" duration="5000">
+          <Initialization sourceURL="init.m4s"/>
+          <SegmentURL media="seg-1.m4s"/>
+          <SegmentURL media="seg-2.m4s"/>
+          <SegmentURL media="seg-3.m4s"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    fn codec_rank(codecs: &str) -> usize {
+        if codecs.starts_with("avc") {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[test]
+    fn parses_segment_timeline() {
+        let mpd = Mpd::parse(LIVE_MPD, "https://example.com/manifest.mpd").unwrap();
+        assert!(mpd.is_live);
+        assert_eq!(mpd.availability_start_time.unwrap().timestamp(), 1704067200);
+        assert_eq!(mpd.minimum_update_period, Some(2.5));
+        let audio = mpd.best_audio().unwrap();
+        assert_eq!(audio.id, "140");
+        assert_eq!(audio.base_url, "https://example.com/live/");
+        let seg = audio.segment.as_ref().unwrap();
+        assert_eq!(seg.media.as_deref(), Some("audio/$Number$.m4s"));
+        assert_eq!(seg.initialization.as_deref(), Some("audio/init.mp4"));
+        assert_eq!((seg.start_number, seg.timescale), (10, 48000));
+        assert_eq!(seg.timeline, [(0, 96000, 2), (288000, 95000, 0)]);
+        assert_eq!(seg.segment_count(), 4);
+        assert_eq!(seg.segment_duration_ms(), Some(1979));
+    }
+
+    #[test]
+    fn inherits_from_the_adaptation_set() {
+        let mpd = Mpd::parse(LIVE_MPD, "https://example.com/manifest.mpd").unwrap();
+        let reps: Vec<&Representation> = mpd.representations().filter(|it| it.is_video()).collect();
+        assert_eq!(reps.len(), 4);
+        for it in reps.iter() {
+            let seg = it.segment.as_ref().unwrap();
+            assert_eq!((seg.timescale, seg.segment_count()), (90000, 4));
+            assert_eq!(seg.segment_duration_ms(), Some(2000));
+        }
+        assert!((reps[2].frame_rate - 59.94).abs() < 0.01);
+        assert_eq!(reps[3].base_url, "https://example.com/live/vp9/");
+    }
+
+    #[test]
+    fn selects_video_by_quality_and_codec() {
+        let mpd = Mpd::parse(LIVE_MPD, "https://example.com/manifest.mpd").unwrap();
+        let pick = |q: &str| mpd.select_video(Quality::parse(q).unwrap(), codec_rank).unwrap().id.clone();
+        // vp9 has the better bandwidth but avc is preferred
+        assert_eq!(pick("best"), "299");
+        assert_eq!(pick("1080p30"), "137");
+        assert_eq!(pick("720p"), "136");
+        assert_eq!(pick("worst"), "136");
+        assert_eq!(pick("480p"), "136");
+        let vp9 = mpd.select_video(Quality::Best, |it| codec_rank(it) ^ 1).unwrap();
+        assert_eq!(vp9.id, "303");
+    }
+
+    #[test]
+    fn parses_segment_list() {
+        let mpd = Mpd::parse(STATIC_MPD, "https://example.com/v.mpd").unwrap();
+        assert!(!mpd.is_live);
+        let rep = mpd.select_video(Quality::Best, codec_rank).unwrap();
+        assert_eq!(rep.codecs, "hev1.1.6.L120.90");
+        assert_eq!((rep.width, rep.height), (1280, 720));
+        assert_eq!(rep.base_url, "https://cdn.example.com/v/");
+        let seg = rep.segment.as_ref().unwrap();
+        assert_eq!(seg.initialization.as_deref(), Some("init.m4s"));
+        assert_eq!(seg.media_urls, ["seg-1.m4s", "seg-2.m4s", "seg-3.m4s"]);
+        assert_eq!(seg.segment_count(), 3);
+        assert_eq!(seg.segment_duration_ms(), Some(5000));
+        assert!(mpd.best_audio().is_none());
+    }
+
+    #[test]
+    fn repeats_to_the_next_entry_or_the_period_end() {
+        let timeline = |root: &str, period: &str| {
+            let text = format!(
+                r#"<MPD {}><Period {}><AdaptationSet mimeType="audio/mp4">
+                <SegmentTemplate timescale="1000" media="$Time$.m4s" presentationTimeOffset="1000">
+                <SegmentTimeline><S d="2000" r="-1"/><S t="9000" d="3000" r="-1"/><S d="1000"/></SegmentTimeline>
+                </SegmentTemplate><Representation id="a" bandwidth="1"/></AdaptationSet></Period></MPD>"#,
+                root, period
+            );
+            let mpd = Mpd::parse(&text, "https://example.com/").unwrap();
+            mpd.best_audio().unwrap().segment.clone().unwrap().timeline
+        };
+        // the second S has no t after it, it runs to the end of the period
+        assert_eq!(
+            timeline("", r#"duration="PT30S""#),
+            [(1000, 2000, 3), (9000, 3000, 7), (33000, 1000, 0)]
+        );
+        assert_eq!(
+            timeline(r#"mediaPresentationDuration="PT29S""#, ""),
+            [(1000, 2000, 3), (9000, 3000, 6), (30000, 1000, 0)]
+        );
+        // a live period has no end yet
+        assert_eq!(
+            timeline(r#"type="dynamic""#, ""),
+            [(1000, 2000, 3), (9000, 3000, 0), (12000, 1000, 0)]
+        );
+    }
+
+    #[test]
+    fn rejects_other_documents() {
+        assert!(Mpd::parse("<html></html>", "https://example.com/").is_err());
+        assert!(Mpd::parse("<MPD>", "https://example.com/").is_err());
+        assert!(Mpd::parse("<MPD type=\"static\"/>", "https://example.com/").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("PT2S"), Some(2.0));
+        assert_eq!(parse_duration("P1D"), None);
+        assert_eq!(parse_frame_rate("30000/1001"), 30000.0 / 1001.0);
+        assert_eq!(parse_frame_rate("25"), 25.0);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::{config::ConfigManager, dmlerr, ipcmanager::IPCManager};
+use log::{info, warn};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    path::PathBuf,
+    rc::Rc,
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+pub const TRACK_VIDEO: usize = 0;
+pub const TRACK_AUDIO: usize = 1;
+const MAX_CHUNK_LEN: i64 = 10000; // in milliseconds
+
+struct ReplayChunk {
+    path: PathBuf,
+    time: i64, // unix time in milliseconds
+}
+
+#[derive(Default)]
+struct ReplayTrack {
+    header: Vec<u8>,
+    // the latest of each kind that goes after the header, like one sequence header per flv tag type
+    header_parts: Vec<(u8, Vec<u8>)>,
+    chunks: VecDeque<ReplayChunk>,
+    pending: Vec<u8>,
+    pending_time: i64,
+}
+
+impl ReplayTrack {
+    // the chunk covering `start`, or the oldest one
+    fn first_chunk(&self, start: i64) -> usize {
+        self.chunks.iter().rposition(|it| it.time <= start).unwrap_or(0)
+    }
+}
+
+// the offset of the first fragment, everything before it is the init segment
+fn mp4_init_len(data: &[u8]) -> usize {
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let box_type = &data[pos + 4..pos + 8];
+        if box_type == b"emsg" || box_type == b"moof" {
+            return pos;
+        }
+        if len < 8 {
+            break;
+        }
+        pos += len;
+    }
+    0
+}
+
+/// Keeps the last few minutes of the input stream on disk, split into chunks that start on a keyframe or a segment.
+#[allow(unused)]
+pub struct ReplayBuffer {
+    dir: PathBuf,
+    window: i64, // in milliseconds
+    seq: Cell<u64>,
+    tracks: RefCell<Vec<ReplayTrack>>,
+    ipc_manager: Rc<IPCManager>,
+    cm: Rc<ConfigManager>,
+}
+
+impl ReplayBuffer {
+    pub fn new(cm: Rc<ConfigManager>, im: Rc<IPCManager>) -> Self {
+        ReplayBuffer {
+            dir: im.get_replay_dir_path(),
+            window: cm.replay_buffer as i64 * 1000,
+            seq: Cell::new(0),
+            tracks: RefCell::new(vec![ReplayTrack::default(), ReplayTrack::default()]),
+            ipc_manager: im,
+            cm,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.window > 0
+    }
+
+    /// Drops everything buffered, the next stream may not continue the old one.
+    pub async fn reset(&self) {
+        let old = self.tracks.replace(vec![ReplayTrack::default(), ReplayTrack::default()]);
+        for c in old.into_iter().flat_map(|it| it.chunks) {
+            let _ = tokio::fs::remove_file(&c.path).await;
+        }
+    }
+
+    /// Keeps the latest stream configuration of a kind (script or sequence header tags) that every clip starts with.
+    /// `reset` drops the media buffered so far, it can not be decoded with the new configuration.
+    pub async fn update_header(&self, track: usize, kind: u8, data: &[u8], reset: bool) {
+        if !self.enabled() {
+            return;
+        }
+        let stale = {
+            let mut tracks = self.tracks.borrow_mut();
+            let t = &mut tracks[track];
+            match t.header_parts.iter_mut().find(|it| it.0 == kind) {
+                Some(it) => it.1 = data.to_vec(),
+                None => t.header_parts.push((kind, data.to_vec())),
+            }
+            if !reset {
+                return;
+            }
+            t.pending.clear();
+            std::mem::take(&mut t.chunks)
+        };
+        for c in stale {
+            let _ = tokio::fs::remove_file(&c.path).await;
+        }
+    }
+
+    pub fn set_header(&self, track: usize, data: &[u8]) {
+        if self.enabled() {
+            let mut tracks = self.tracks.borrow_mut();
+            tracks[track].header.clear();
+            tracks[track].header.extend_from_slice(data);
+            tracks[track].header_parts.clear();
+        }
+    }
+
+    /// Buffers stream data, `boundary` tells a chunk may start here.
+    pub async fn push(&self, track: usize, data: &[u8], boundary: bool) {
+        if !self.enabled() {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        let full = {
+            let mut tracks = self.tracks.borrow_mut();
+            let t = &mut tracks[track];
+            if t.pending.is_empty() {
+                t.pending_time = now;
+            }
+            let flush = boundary || now - t.pending_time > MAX_CHUNK_LEN;
+            let full = if flush && !t.pending.is_empty() {
+                Some((
+                    std::mem::take(&mut t.pending),
+                    std::mem::replace(&mut t.pending_time, now),
+                ))
+            } else {
+                None
+            };
+            t.pending.extend_from_slice(data);
+            full
+        };
+        if let Some((data, time)) = full {
+            // playback goes on without it
+            if let Err(e) = self.write_chunk(track, data, time).await {
+                warn!("replay: write chunk error: {}", e);
+            }
+        }
+    }
+
+    /// Buffers a whole HLS or DASH segment, the first one may carry the mp4 init segment.
+    pub async fn push_segment(&self, track: usize, data: &[u8], is_header: bool) {
+        if is_header {
+            let init_len = mp4_init_len(data);
+            self.set_header(track, &data[..init_len]);
+            return self.push(track, &data[init_len..], true).await;
+        }
+        self.push(track, data, true).await
+    }
+
+    async fn write_chunk(&self, track: usize, data: Vec<u8>, time: i64) -> anyhow::Result<()> {
+        if self.seq.get() == 0 {
+            tokio::fs::create_dir_all(&self.dir).await?;
+        }
+        let path = self.dir.join(format!("{}-{}", track, self.seq.get()));
+        self.seq.set(self.seq.get() + 1);
+        tokio::fs::write(&path, &data).await?;
+        let expired: Vec<ReplayChunk> = {
+            let mut tracks = self.tracks.borrow_mut();
+            let t = &mut tracks[track];
+            t.chunks.push_back(ReplayChunk { path, time });
+            let n = t.chunks.iter().take_while(|it| time - it.time > self.window).count();
+            t.chunks.drain(..n).collect()
+        };
+        for c in expired {
+            let _ = tokio::fs::remove_file(&c.path).await;
+        }
+        Ok(())
+    }
+
+    /// Unix time in milliseconds a clip of the last `secs` seconds starts at.
+    pub fn clip_start(&self, secs: u64) -> Option<i64> {
+        let tracks = self.tracks.borrow();
+        let t = tracks.iter().find(|it| !it.chunks.is_empty() || !it.pending.is_empty())?;
+        let start = chrono::Utc::now().timestamp_millis() - secs as i64 * 1000;
+        match t.chunks.get(t.first_chunk(start)) {
+            Some(it) => Some(it.time),
+            None => Some(t.pending_time),
+        }
+    }
+
+    async fn dump_track(&self, track: usize, start: i64) -> anyhow::Result<Option<PathBuf>> {
+        let (header, paths, pending) = {
+            let tracks = self.tracks.borrow();
+            let t = &tracks[track];
+            if t.chunks.is_empty() && t.pending.is_empty() {
+                return Ok(None);
+            }
+            let paths: Vec<PathBuf> = t.chunks.iter().skip(t.first_chunk(start)).map(|it| it.path.clone()).collect();
+            let mut header = t.header.clone();
+            for (_, it) in t.header_parts.iter() {
+                header.extend_from_slice(it);
+            }
+            (header, paths, t.pending.clone())
+        };
+        let out = self.dir.join(format!("clip-{}", track));
+        let mut f = tokio::fs::File::create(&out).await?;
+        f.write_all(&header).await?;
+        for p in paths.iter() {
+            // may have been evicted meanwhile
+            if let Ok(data) = tokio::fs::read(p).await {
+                f.write_all(&data).await?;
+            }
+        }
+        f.write_all(&pending).await?;
+        f.flush().await?;
+        Ok(Some(out))
+    }
+
+    /// Writes the buffer from `start` on with the given danmaku subtitle into a mkv, returns its path.
+    pub async fn write_clip(&self, start: i64, ass: &str) -> anyhow::Result<PathBuf> {
+        let mut inputs = Vec::new();
+        for track in [TRACK_VIDEO, TRACK_AUDIO] {
+            if let Some(it) = self.dump_track(track, start).await? {
+                inputs.push(it);
+            }
+        }
+        if inputs.is_empty() {
+            return Err(dmlerr!());
+        }
+        let ass_path = self.dir.join("clip.ass");
+        tokio::fs::write(&ass_path, ass).await?;
+
+        let filename = self.cm.record_filename(&chrono::Local::now(), "clip");
+        if let Some(it) = filename.parent() {
+            tokio::fs::create_dir_all(it).await?;
+        }
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-y", "-hide_banner", "-nostdin", "-loglevel", "error"]);
+        for it in inputs.iter() {
+            cmd.arg("-i").arg(it);
+        }
+        cmd.arg("-i").arg(&ass_path);
+        if inputs.len() > 1 {
+            cmd.args(["-map", "0:v:0?", "-map", "1:a:0?", "-map", "2:s:0"]);
+        } else {
+            cmd.args(["-map", "0:v:0?", "-map", "0:a:0?", "-map", "1:s:0"]);
+        }
+        cmd.args(["-c", "copy"]);
+        cmd.args([
+            "-metadata",
+            format!("title={}", self.cm.title.borrow()).as_str(),
+            "-f",
+            "matroska",
+        ]);
+        cmd.arg(&filename);
+        let status = cmd.stdin(std::process::Stdio::null()).kill_on_drop(true).status().await?;
+        for it in inputs.iter() {
+            let _ = tokio::fs::remove_file(it).await;
+        }
+        let _ = tokio::fs::remove_file(&ass_path).await;
+        if !status.success() {
+            return Err(anyhow::anyhow!("ffmpeg exited with {}", status));
+        }
+        info!(
+            "replay: clip from {} written to {}",
+            start,
+            filename.display()
+        );
+        Ok(filename)
+    }
+}
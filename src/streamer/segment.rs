@@ -1,3 +1,4 @@
+use crate::dmlerr;
 use std::{
     cell::{Cell, RefCell},
     collections::{HashMap, VecDeque},
@@ -14,36 +15,47 @@ pub struct MediaSegment {
 }
 
 pub struct SegmentStream {
+    keep_backlog: bool,
     sequence: Cell<u64>,
     pub refresh_itvl: Cell<u64>, // in ms
     clips: RefCell<VecDeque<(MediaSegment, bool)>>,
     clip_tx: Sender<MediaSegment>, // clip, is_skip
-    pub clip_rx: RefCell<Receiver<MediaSegment>>,
+    clip_rx: Cell<Option<Receiver<MediaSegment>>>,
     refresh_tx: Sender<bool>,
-    pub refresh_rx: RefCell<Receiver<bool>>,
+    refresh_rx: Cell<Option<Receiver<bool>>>,
 }
 
 impl SegmentStream {
-    pub fn new() -> Self {
+    pub fn new(keep_backlog: bool) -> Self {
         let (tx, rx) = tokio::sync::mpsc::channel(100);
         let (tx1, rx1) = tokio::sync::mpsc::channel(10);
         Self {
+            keep_backlog,
             sequence: Cell::new(0),
             refresh_itvl: Cell::new(1000),
             clips: RefCell::new(VecDeque::new()),
             clip_tx: tx,
-            clip_rx: RefCell::new(rx),
+            clip_rx: Cell::new(Some(rx)),
             refresh_tx: tx1,
-            refresh_rx: RefCell::new(rx1),
+            refresh_rx: Cell::new(Some(rx1)),
         }
     }
 
+    // each receiver has one reader, it keeps it across awaits instead of a borrow
+    pub fn take_clip_rx(&self) -> anyhow::Result<Receiver<MediaSegment>> {
+        self.clip_rx.take().ok_or_else(|| dmlerr!())
+    }
+
+    pub fn take_refresh_rx(&self) -> anyhow::Result<Receiver<bool>> {
+        self.refresh_rx.take().ok_or_else(|| dmlerr!())
+    }
+
     pub async fn update_sequence(&self, sq: u64, clips: VecDeque<MediaSegment>, itvl: u64) -> anyhow::Result<()> {
         self.refresh_itvl.set(itvl);
         let mut old_clips = self.clips.borrow_mut();
         // info!("{:?}\n{:?}", &clips, &old_clips);
         let mut first_update = false;
-        if old_clips.is_empty() {
+        if old_clips.is_empty() && !self.keep_backlog {
             first_update = true
         }
         for (i, clip) in clips.into_iter().enumerate() {
@@ -53,10 +65,6 @@ impl SegmentStream {
             }
         }
 
-        while old_clips.len() > 15 {
-            old_clips.pop_front();
-        }
-
         let len = old_clips.len();
         for (i, c) in old_clips.iter_mut().enumerate() {
             if c.1 == false {
@@ -76,6 +84,10 @@ impl SegmentStream {
                 }
             }
         }
+
+        while old_clips.len() > 15 {
+            old_clips.pop_front();
+        }
         Ok(())
     }
 
@@ -3,31 +3,44 @@ use crate::{
     dmlerr,
     dmlive::DMLMessage,
     ipcmanager::{DMLStream, IPCManager},
-    streamer::segment::{MediaSegment, SegmentStream},
+    streamer::{
+        mpd::{Mpd, Representation},
+        replay::{ReplayBuffer, TRACK_AUDIO, TRACK_VIDEO},
+        segment::{MediaSegment, SegmentStream},
+    },
 };
 use bytes::{Buf, BytesMut};
 use log::info;
 use reqwest::{Client, Response};
 use std::{
-    cell::Cell,
+    cell::{Cell, RefCell},
     collections::{HashMap, VecDeque},
     rc::Rc,
 };
 use tokio::sync::mpsc::{self, Receiver};
 use tokio::{io::AsyncWriteExt, sync::mpsc::Sender};
 
-fn get_head_sq_and_time(resp: &Response) -> anyhow::Result<(u64, u64)> {
-    let sq: u64 = resp.headers().get("X-Head-Seqnum").ok_or_else(|| dmlerr!())?.to_str()?.parse()?;
-    let ti: u64 = resp.headers().get("X-Head-Time-Sec").ok_or_else(|| dmlerr!())?.to_str()?.parse()?;
-    Ok((sq, ti))
+fn get_sq_range(rep: &Representation) -> Option<(u64, u64)> {
+    let seg = rep.segment.as_ref()?;
+    let media_sq = |media: &String| {
+        let mut it = media.split('/');
+        it.find(|s| s.eq(&"sq"))?;
+        it.next()?.parse::<u64>().ok()
+    };
+    let first = seg.media_urls.first().and_then(media_sq).unwrap_or(seg.start_number);
+    let last = seg.media_urls.last().and_then(media_sq).unwrap_or(first + seg.segment_count().saturating_sub(1));
+    Some((first, last))
 }
 
 #[allow(unused)]
 pub struct Youtube {
-    url_v: String,
-    url_a: String,
+    mpd_url: String,
+    url_v: RefCell<String>,
+    url_a: RefCell<String>,
+    first_sq: Cell<u64>,
     sq: Cell<u64>,
     itvl: Cell<u64>,
+    replay: Rc<ReplayBuffer>,
     ipc_manager: Rc<IPCManager>,
     cm: Rc<ConfigManager>,
     mtx: async_channel::Sender<DMLMessage>,
@@ -35,20 +48,56 @@ pub struct Youtube {
 
 impl Youtube {
     pub fn new(
-        url_v: String, url_a: String, sq: u64, cm: Rc<ConfigManager>, im: Rc<IPCManager>,
+        mpd_url: String, replay: Rc<ReplayBuffer>, cm: Rc<ConfigManager>, im: Rc<IPCManager>,
         mtx: async_channel::Sender<DMLMessage>,
     ) -> Self {
         Youtube {
-            url_v,
-            url_a,
-            sq: Cell::new(sq),
+            mpd_url,
+            url_v: RefCell::new("".into()),
+            url_a: RefCell::new("".into()),
+            first_sq: Cell::new(0),
+            sq: Cell::new(0),
             itvl: Cell::new(1000),
+            replay,
             ipc_manager: im,
             cm,
             mtx,
         }
     }
 
+    async fn load_mpd(&self, client: &Client) -> anyhow::Result<()> {
+        let resp = client
+            .get(&self.mpd_url)
+            .header("Accept-Language", "en-US")
+            .header("Referer", "https://www.youtube.com/")
+            .send()
+            .await?
+            .text()
+            .await?;
+        let mpd = Mpd::parse(&resp, &self.mpd_url)?;
+        let v = mpd
+            .select_video(self.cm.quality.get(), |it| self.cm.codec_rank(it))
+            .ok_or_else(|| anyhow::anyhow!("no video representation"))?;
+        let a = mpd.best_audio().ok_or_else(|| anyhow::anyhow!("no audio representation"))?;
+        info!(
+            "youtube: video {} {}x{} {}, audio {} {}",
+            &v.id, v.width, v.height, &v.codecs, &a.id, &a.codecs
+        );
+        let (first_sq, sq) = get_sq_range(a).ok_or_else(|| dmlerr!())?;
+        self.first_sq.set(first_sq);
+        self.sq.set(sq);
+        let itvl = a
+            .segment
+            .as_ref()
+            .and_then(|it| it.segment_duration_ms())
+            .or_else(|| mpd.minimum_update_period.map(|it| (it * 1000.0) as u64))
+            .unwrap_or(5000);
+        self.itvl.set(itvl.max(1000));
+        *self.url_v.borrow_mut() = v.base_url.clone();
+        *self.url_a.borrow_mut() = a.base_url.clone();
+        Ok(())
+    }
+
     pub async fn strip_mp4_header(&self, resp: &mut Response) -> anyhow::Result<BytesMut> {
         let mut buf = BytesMut::with_capacity(4000);
         while let Some(chunk) = resp.chunk().await? {
@@ -81,7 +130,7 @@ impl Youtube {
         if seg.skip != 0 {
             return Ok(());
         }
-        let u = format!("{}sq/{}", &self.url_a, &seg.url);
+        let u = format!("{}sq/{}", self.url_a.borrow(), &seg.url);
         info!("a: {}", &u);
         let mut resp = client
             .get(u)
@@ -89,13 +138,18 @@ impl Youtube {
             .header("Referer", "https://www.youtube.com/")
             .send()
             .await?;
+        let mut buf = BytesMut::new();
         if !seg.is_header && seg.skip == 0 {
             let d = self.strip_mp4_header(&mut resp).await?;
             stream.write_all(&d).await?;
+            buf.extend_from_slice(&d);
         }
         while let Some(chunk) = resp.chunk().await? {
             stream.write_all(&chunk).await?;
+            buf.extend_from_slice(&chunk);
         }
+        self.cm.metrics.bytes_downloaded.add(buf.len() as u64);
+        self.replay.push_segment(TRACK_AUDIO, &buf, seg.is_header).await;
         Ok(())
     }
 
@@ -105,7 +159,7 @@ impl Youtube {
         if seg.skip == 2 {
             return Ok(());
         }
-        let u = format!("{}sq/{}", &self.url_v, &seg.url);
+        let u = format!("{}sq/{}", self.url_v.borrow(), &seg.url);
         info!("v: {}", &u);
         let mut resp = client
             .get(u)
@@ -113,62 +167,77 @@ impl Youtube {
             .header("Referer", "https://www.youtube.com/")
             .send()
             .await?;
-        if seg.skip == 1 {
-            let (sq, ti) = get_head_sq_and_time(&resp)?;
-            self.sq.set(sq);
-            let itvl = (ti as f64 / (sq + 1) as f64).round() as u64;
-            if itvl != 0 {
-                self.itvl.set(itvl * 1000);
-            }
-        }
+        let mut buf = BytesMut::new();
         if !seg.is_header && seg.skip == 0 {
             let d = self.strip_mp4_header(&mut resp).await?;
             stream.write_all(&d).await?;
+            buf.extend_from_slice(&d);
         }
         while let Some(chunk) = resp.chunk().await? {
             if seg.skip == 0 {
                 stream.write_all(&chunk).await?;
+                buf.extend_from_slice(&chunk);
             }
         }
+        self.cm.metrics.bytes_downloaded.add(buf.len() as u64);
+        if seg.skip == 0 {
+            self.replay.push_segment(TRACK_VIDEO, &buf, seg.is_header).await;
+        }
         Ok(())
     }
 
     pub async fn refresh_seq_task(&self, ss: &SegmentStream) -> anyhow::Result<()> {
-        let mut rx = ss.refresh_rx.borrow_mut();
-        let mut sq = self.sq.get().saturating_sub(1);
-        let mut state = 0;
+        let mut rx = ss.take_refresh_rx()?;
+        let mut sq = self.sq.get();
+        let mut first = true;
         while let Some(_) = rx.recv().await {
             let mut clips = VecDeque::new();
-            let skip = if state == 0 {
-                state = 1;
-                1
-            } else if state == 1 {
-                sq = self.sq.get();
-                state = 2;
-                0
-            } else if state == 2 {
-                state = 3;
-                0
+            let start_sq = if first {
+                let rewind = self.cm.rewind.get();
+                if rewind > 0 {
+                    sq.saturating_sub(rewind * 1000 / self.itvl.get()).max(self.first_sq.get())
+                } else if self.cm.from_start {
+                    self.first_sq.get().min(sq)
+                } else {
+                    sq
+                }
             } else {
                 sq += 1;
-                0
-            };
-            let c = MediaSegment {
-                skip,
-                props: HashMap::new(),
-                url: sq.to_string(),
-                is_header: if state == 2 { true } else { false },
+                sq
             };
-            clips.push_back(c);
-            ss.update_sequence(sq, clips, self.itvl.get()).await?;
+            for i in start_sq..=sq {
+                let c = MediaSegment {
+                    skip: 0,
+                    props: HashMap::new(),
+                    url: i.to_string(),
+                    is_header: first && i == start_sq,
+                };
+                clips.push_back(c);
+            }
+            first = false;
+            ss.update_sequence(start_sq, clips, self.itvl.get()).await?;
         }
         Ok(())
     }
 
+    fn observe_fetch(&self, start: std::time::Instant, res: &anyhow::Result<()>) {
+        match res {
+            Ok(_) => self.cm.metrics.segment_fetch.observe(start.elapsed().as_secs_f64()),
+            Err(_) => self.cm.metrics.segment_failures.inc(),
+        }
+    }
+
     pub async fn video_task(&self, client: &Client, mut rx: Receiver<MediaSegment>) -> anyhow::Result<()> {
+        if self.cm.audio_only {
+            while rx.recv().await.is_some() {}
+            return Ok(());
+        }
         let mut video_stream = self.ipc_manager.get_video_socket().await?;
         while let Some(clip) = rx.recv().await {
-            self.download_video(&client, &mut video_stream, &clip).await?;
+            let start = std::time::Instant::now();
+            let res = self.download_video(&client, &mut video_stream, &clip).await;
+            self.observe_fetch(start, &res);
+            res?;
         }
         Ok(())
     }
@@ -176,7 +245,10 @@ impl Youtube {
     pub async fn audio_task(&self, client: &Client, mut rx: Receiver<MediaSegment>) -> anyhow::Result<()> {
         let mut audio_stream = self.ipc_manager.get_audio_socket().await?;
         while let Some(clip) = rx.recv().await {
-            self.download_audio(&client, &mut audio_stream, &clip).await?;
+            let start = std::time::Instant::now();
+            let res = self.download_audio(&client, &mut audio_stream, &clip).await;
+            self.observe_fetch(start, &res);
+            res?;
         }
         Ok(())
     }
@@ -184,7 +256,7 @@ impl Youtube {
     pub async fn dispatch_task(
         &self, ss: &SegmentStream, tx_v: Sender<MediaSegment>, tx_a: Sender<MediaSegment>,
     ) -> anyhow::Result<()> {
-        let mut rx = ss.clip_rx.borrow_mut();
+        let mut rx = ss.take_clip_rx()?;
         while let Some(clip) = rx.recv().await {
             info!("youtube: clip {}", &clip.url);
             tx_v.send(clip.clone()).await?;
@@ -198,7 +270,8 @@ impl Youtube {
             .user_agent(crate::utils::gen_ua())
             .timeout(tokio::time::Duration::from_secs(15))
             .build()?;
-        let seg_stream = SegmentStream::new();
+        self.load_mpd(&client).await?;
+        let seg_stream = SegmentStream::new(self.cm.from_start || self.cm.rewind.get() > 0);
         let (tx_v, rx_v) = mpsc::channel(100);
         let (tx_a, rx_a) = mpsc::channel(100);
         tokio::select! {
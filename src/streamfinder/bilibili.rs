@@ -1,15 +1,29 @@
+use super::StreamCandidate;
 use crate::dmlerr;
-use crate::{config::ConfigManager, utils::cookies::get_cookies_from_browser};
+use crate::streamer::mpd::{parse_frame_rate, AdaptationSet, Mpd, Representation};
+use crate::{
+    config::{ConfigManager, Quality},
+    utils::cookies::get_cookies_from_browser,
+};
 use anyhow::Result;
 use log::info;
 use regex::Regex;
-use std::{collections::HashMap, rc::Rc};
+use std::rc::Rc;
 use url::Url;
 
 const BILI_API1: &'static str = "https://api.live.bilibili.com/xlive/web-room/v2/index/getRoomPlayInfo";
 const BILI_API2: &'static str = "https://api.live.bilibili.com/xlive/web-room/v1/index/getInfoByRoom";
 const BILI_APIV: &'static str = "https://api.bilibili.com/x/player/playurl";
 const BILI_APIV_EP: &'static str = "https://api.bilibili.com/pgc/player/web/playurl";
+// height and qn of the live tiers, best first
+const BILI_LIVE_QN: [(u64, &str); 6] = [
+    (2160, "20000"),
+    (1080, "10000"),
+    (1080, "400"),
+    (720, "250"),
+    (480, "150"),
+    (360, "80"),
+];
 
 pub struct Bilibili {
     cm: Rc<ConfigManager>,
@@ -20,7 +34,7 @@ impl Bilibili {
         Bilibili { cm }
     }
 
-    pub async fn get_live(&self, room_url: &str) -> Result<HashMap<String, String>> {
+    pub async fn get_live(&self, room_url: &str) -> Result<(String, Vec<StreamCandidate>)> {
         let rid = Url::parse(room_url)?
             .path_segments()
             .ok_or_else(|| dmlerr!())?
@@ -32,7 +46,10 @@ impl Bilibili {
             .connect_timeout(tokio::time::Duration::from_secs(10))
             .build()?;
 
-        let mut ret = HashMap::new();
+        let qn = match self.cm.quality.get() {
+            Quality::Source => "10000",
+            it => it.pick(&BILI_LIVE_QN, |it| (it.0, 0)).map(|it| it.1).unwrap_or("20000"),
+        };
         let mut param1 = Vec::new();
         // room_id=114514&protocol=0,1&format=0,1,2&codec=0,1,2&qn=10000&platform=web&ptype=8&dolby=5&panorama=1
         param1.push(("room_id", rid.as_str()));
@@ -40,8 +57,13 @@ impl Bilibili {
         // param1.push(("mask", "1"));
         param1.push(("protocol", "0,1"));
         param1.push(("format", "0,1,2"));
-        param1.push(("codec", "0,1"));
-        param1.push(("qn", "20000"));
+        let codec = if self.cm.codec_preference.iter().any(|it| it.eq("av1")) {
+            "0,1,2"
+        } else {
+            "0,1"
+        };
+        param1.push(("codec", codec));
+        param1.push(("qn", qn));
         param1.push(("platform", "web"));
         param1.push(("ptype", "8"));
         param1.push(("dolby", "5"));
@@ -62,31 +84,57 @@ impl Bilibili {
             .json::<serde_json::Value>()
             .await?;
         info!("{}", &resp.to_string());
-        let j = resp.pointer("/data/playurl_info/playurl/stream/0/format/0/codec/0").ok_or_else(|| dmlerr!())?;
-        ret.insert(
-            String::from("url"),
-            format!(
-                "{}{}{}",
-                j.pointer("/url_info/0/host").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
-                j.pointer("/base_url").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
-                j.pointer("/url_info/0/extra").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
-            ),
-        );
+        let mut cands = Vec::new();
+        let streams =
+            resp.pointer("/data/playurl_info/playurl/stream").and_then(|it| it.as_array()).ok_or_else(|| dmlerr!())?;
+        // keep the api order unless a codec is preferred, the first codec of the first format is what the web player picks
+        for s in streams {
+            for f in s.pointer("/format").and_then(|it| it.as_array()).into_iter().flatten() {
+                let format_name = f.pointer("/format_name").and_then(|it| it.as_str()).unwrap_or("");
+                for c in f.pointer("/codec").and_then(|it| it.as_array()).into_iter().flatten() {
+                    let base_url = c.pointer("/base_url").and_then(|it| it.as_str()).ok_or_else(|| dmlerr!())?;
+                    for u in c.pointer("/url_info").and_then(|it| it.as_array()).into_iter().flatten() {
+                        let host = u.pointer("/host").and_then(|it| it.as_str()).ok_or_else(|| dmlerr!())?;
+                        let extra = u.pointer("/extra").and_then(|it| it.as_str()).ok_or_else(|| dmlerr!())?;
+                        cands.push(StreamCandidate {
+                            url: format!("{}{}{}", host, base_url, extra),
+                            protocol: format_name.to_string(),
+                            codec: c.pointer("/codec_name").and_then(|it| it.as_str()).unwrap_or("").to_string(),
+                            quality: c.pointer("/current_qn").and_then(|it| it.as_u64()).unwrap_or(0).to_string(),
+                            cdn: Url::parse(host)
+                                .ok()
+                                .and_then(|it| it.host_str().map(|it| it.to_string()))
+                                .unwrap_or_default(),
+                        });
+                    }
+                }
+            }
+        }
+        if cands.is_empty() {
+            return Err(dmlerr!());
+        }
+        cands.sort_by_key(|it| self.cm.codec_rank(&it.codec));
         param1.clear();
         param1.push(("room_id", rid.as_str()));
         let resp = client.get(BILI_API2).query(&param1).send().await?.json::<serde_json::Value>().await?;
-        ret.insert(
-            String::from("title"),
-            format!(
-                "{} - {}",
-                resp.pointer("/data/room_info/title").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
-                resp.pointer("/data/anchor_info/base_info/uname")
-                    .ok_or_else(|| dmlerr!())?
-                    .as_str()
-                    .ok_or_else(|| dmlerr!())?
-            ),
+        let uname = resp
+            .pointer("/data/anchor_info/base_info/uname")
+            .ok_or_else(|| dmlerr!())?
+            .as_str()
+            .ok_or_else(|| dmlerr!())?;
+        let title = format!(
+            "{} - {}",
+            resp.pointer("/data/room_info/title").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
+            uname
         );
-        Ok(ret)
+        *self.cm.streamer.borrow_mut() = uname.to_string();
+        *self.cm.cover_url.borrow_mut() = resp
+            .pointer("/data/room_info/cover")
+            .or_else(|| resp.pointer("/data/anchor_info/base_info/face"))
+            .and_then(|it| it.as_str())
+            .unwrap_or("")
+            .to_string();
+        Ok((title, cands))
     }
     pub async fn get_page_info_ep(
         &self, video_url: &str, mut page: usize,
@@ -99,8 +147,9 @@ impl Bilibili {
         let re = Regex::new(r"_NEXT_DATA_.+>\s*(\{.+\})\s*<").unwrap();
         let j: serde_json::Value = serde_json::from_str(re.captures(&resp).ok_or_else(|| dmlerr!())?[1].as_ref())?;
         // println!("{:?}", &j);
-        let j =
-            j.pointer("/props/pageProps/dehydratedState/queries/0/state/data/seasonInfo/mediaInfo").ok_or_else(|| dmlerr!())?;
+        let j = j
+            .pointer("/props/pageProps/dehydratedState/queries/0/state/data/seasonInfo/mediaInfo")
+            .ok_or_else(|| dmlerr!())?;
         let season_type = j.pointer("/season_type").ok_or_else(|| dmlerr!())?.as_i64().unwrap().to_string();
         let eplist = j.pointer("/episodes").ok_or_else(|| dmlerr!())?.as_array().unwrap();
         let epid = url::Url::parse(video_url)?
@@ -158,6 +207,8 @@ impl Bilibili {
         let bvid = j.pointer("/videoData/bvid").ok_or_else(|| dmlerr!())?.as_str().unwrap().to_string();
         let title = j.pointer("/videoData/title").ok_or_else(|| dmlerr!())?.as_str().unwrap();
         let artist = j.pointer("/videoData/owner/name").ok_or_else(|| dmlerr!())?.as_str().unwrap().to_string();
+        *self.cm.cover_url.borrow_mut() =
+            j.pointer("/videoData/pic").and_then(|it| it.as_str()).unwrap_or("").to_string();
         let j = j.pointer("/videoData/pages").ok_or_else(|| dmlerr!())?.as_array().unwrap();
         if page == 0 {
             page = 1;
@@ -182,6 +233,51 @@ impl Bilibili {
         Ok((bvid, cid, final_title, artist))
     }
 
+    fn decode_dash(j: &serde_json::Value) -> Result<Mpd> {
+        let rep = |ele: &serde_json::Value, mime_type: &str| -> Result<Representation> {
+            Ok(Representation {
+                id: ele.pointer("/id").ok_or_else(|| dmlerr!())?.as_u64().unwrap_or(0).to_string(),
+                mime_type: ele.pointer("/mimeType").and_then(|it| it.as_str()).unwrap_or(mime_type).to_string(),
+                codecs: ele.pointer("/codecs").and_then(|it| it.as_str()).unwrap_or("").to_string(),
+                bandwidth: ele.pointer("/bandwidth").and_then(|it| it.as_u64()).unwrap_or(0),
+                width: ele.pointer("/width").and_then(|it| it.as_u64()).unwrap_or(0),
+                height: ele.pointer("/height").and_then(|it| it.as_u64()).unwrap_or(0),
+                frame_rate: ele.pointer("/frameRate").and_then(|it| it.as_str()).map(parse_frame_rate).unwrap_or(0.0),
+                base_url: ele
+                    .pointer("/base_url")
+                    .ok_or_else(|| dmlerr!())?
+                    .as_str()
+                    .ok_or_else(|| dmlerr!())?
+                    .to_string(),
+                segment: None,
+            })
+        };
+        let mut videos = Vec::new();
+        let mut audios = Vec::new();
+        for ele in j.pointer("/dash/video").ok_or_else(|| dmlerr!())?.as_array().ok_or_else(|| dmlerr!())? {
+            videos.push(rep(ele, "video/mp4")?);
+        }
+        for ele in j.pointer("/dash/audio").ok_or_else(|| dmlerr!())?.as_array().ok_or_else(|| dmlerr!())? {
+            audios.push(rep(ele, "audio/mp4")?);
+        }
+        if let Some(ele) = j.pointer("/dash/flac/audio").filter(|it| !it.is_null()) {
+            audios.push(rep(ele, "audio/mp4")?);
+        }
+        Ok(Mpd {
+            adaptation_sets: vec![
+                AdaptationSet {
+                    mime_type: "video/mp4".into(),
+                    representations: videos,
+                },
+                AdaptationSet {
+                    mime_type: "audio/mp4".into(),
+                    representations: audios,
+                },
+            ],
+            ..Default::default()
+        })
+    }
+
     pub async fn get_video(&self, page: usize) -> Result<Vec<String>> {
         let cookies = if self.cm.cookies_from_browser.is_empty() {
             self.cm.bcookie.clone()
@@ -219,35 +315,14 @@ impl Bilibili {
                 .await?;
             // println!("{:?}", &resp);
             let j = resp.pointer("/result").ok_or_else(|| dmlerr!())?;
-            let mut videos = HashMap::new();
-            let mut audios = HashMap::new();
-            for ele in j.pointer("/dash/video").ok_or_else(|| dmlerr!())?.as_array().unwrap() {
-                let mut id = ele.pointer("/id").ok_or_else(|| dmlerr!())?.as_u64().unwrap() * 10;
-                if ele.pointer("/codecid").ok_or_else(|| dmlerr!())?.as_u64().eq(&Some(7)) {
-                    id += 1;
-                }
-                videos.insert(
-                    id,
-                    ele.pointer("/base_url").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                );
-            }
-            for ele in j.pointer("/dash/audio").ok_or_else(|| dmlerr!())?.as_array().unwrap() {
-                audios.insert(
-                    ele.pointer("/id").ok_or_else(|| dmlerr!())?.as_u64().unwrap(),
-                    ele.pointer("/base_url").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                );
-            }
-            if let Some(ele) = j.pointer("/dash/flac/audio") {
-                audios.insert(
-                    ele.pointer("/id").ok_or_else(|| dmlerr!())?.as_u64().unwrap() + 100,
-                    ele.pointer("/base_url").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                );
-            }
-            ret.push(videos.iter().max_by_key(|x| x.0).unwrap().1.to_string());
-            ret.push(audios.iter().max_by_key(|x| x.0).unwrap().1.to_string());
+            let mpd = Self::decode_dash(j)?;
+            let v = mpd.select_video(self.cm.quality.get(), |it| self.cm.codec_rank(it)).ok_or_else(|| dmlerr!())?;
+            ret.push(v.base_url.clone());
+            ret.push(mpd.best_audio().ok_or_else(|| dmlerr!())?.base_url.clone());
         } else {
             let u = self.cm.bvideo_info.borrow().base_url.clone();
-            let (bvid, cid, title, _artist) = self.get_page_info(&u, page).await?;
+            let (bvid, cid, title, artist) = self.get_page_info(&u, page).await?;
+            *self.cm.streamer.borrow_mut() = artist;
             // println!("{} {} {} {}", &bvid, &cid, &title, &artist);
             ret.push(title);
             ret.push(cid.clone());
@@ -268,32 +343,10 @@ impl Bilibili {
                 .json::<serde_json::Value>()
                 .await?;
             let j = resp.pointer("/data").ok_or_else(|| dmlerr!())?;
-            let mut videos = HashMap::new();
-            let mut audios = HashMap::new();
-            for ele in j.pointer("/dash/video").ok_or_else(|| dmlerr!())?.as_array().unwrap() {
-                let mut id = ele.pointer("/id").ok_or_else(|| dmlerr!())?.as_u64().unwrap() * 10;
-                if ele.pointer("/codecid").ok_or_else(|| dmlerr!())?.as_u64().eq(&Some(7)) {
-                    id += 1;
-                }
-                videos.insert(
-                    id,
-                    ele.pointer("/base_url").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                );
-            }
-            for ele in j.pointer("/dash/audio").ok_or_else(|| dmlerr!())?.as_array().unwrap() {
-                audios.insert(
-                    ele.pointer("/id").ok_or_else(|| dmlerr!())?.as_u64().unwrap(),
-                    ele.pointer("/base_url").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                );
-            }
-            if let Some(ele) = j.pointer("/dash/flac/audio") {
-                audios.insert(
-                    ele.pointer("/id").ok_or_else(|| dmlerr!())?.as_u64().unwrap() + 100,
-                    ele.pointer("/base_url").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                );
-            }
-            ret.push(videos.iter().max_by_key(|x| x.0).unwrap().1.to_string());
-            ret.push(audios.iter().max_by_key(|x| x.0).unwrap().1.to_string());
+            let mpd = Self::decode_dash(j)?;
+            let v = mpd.select_video(self.cm.quality.get(), |it| self.cm.codec_rank(it)).ok_or_else(|| dmlerr!())?;
+            ret.push(v.base_url.clone());
+            ret.push(mpd.best_audio().ok_or_else(|| dmlerr!())?.base_url.clone());
         }
         Ok(ret)
     }
@@ -1,10 +1,15 @@
 // refer to https://github.com/SeaHOH/ykdl
 use chrono::prelude::*;
+use log::info;
 use regex::Regex;
-use std::collections::HashMap;
+use std::rc::Rc;
 use uuid::Uuid;
 
-use crate::dmlerr;
+use super::{tier_height, StreamCandidate};
+use crate::{
+    config::{ConfigManager, Quality},
+    dmlerr,
+};
 
 const DOUYU_API1: &'static str = "https://www.douyu.com/betard/";
 // const DOUYU_API2: &'static str = "https://open.douyucdn.cn/api/RoomApi/room/";
@@ -27,13 +32,70 @@ fn get_js_md5() -> String {
     ret
 }
 
-pub struct Douyu {}
+struct H5Play {
+    cand: StreamCandidate,
+    cdns: Vec<String>,
+    rates: Vec<(u64, u64)>, // height, rate
+}
+
+pub struct Douyu {
+    cm: Rc<ConfigManager>,
+}
 impl Douyu {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(cm: Rc<ConfigManager>) -> Self {
+        Self { cm }
+    }
+    async fn get_h5_play(
+        client: &reqwest::Client, rid: &str, param1: &[(&str, String)], cdn: &str, rate: u64,
+    ) -> anyhow::Result<H5Play> {
+        let mut param1 = param1.to_vec();
+        param1.push(("cdn", cdn.to_string()));
+        param1.push(("rate", rate.to_string()));
+        let resp = client
+            .post(format!("{}{}", DOUYU_API3, rid))
+            .header("User-Agent", crate::utils::gen_ua())
+            .header("Referer", "https://www.douyu.com/")
+            .form(&param1)
+            .send()
+            .await?
+            .json::<serde_json::Value>()
+            .await?;
+        // println!("{:?}", &resp);
+        let mut ret = StreamCandidate::new(format!(
+            "{}/{}",
+            resp.pointer("/data/rtmp_url").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
+            resp.pointer("/data/rtmp_live").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?
+        ));
+        ret.protocol = if ret.url.contains(".m3u8") { "hls" } else { "flv" }.into();
+        ret.quality = resp.pointer("/data/rate").and_then(|it| it.as_u64()).unwrap_or(0).to_string();
+        ret.cdn = resp.pointer("/data/rtmp_cdn").and_then(|it| it.as_str()).unwrap_or("").to_string();
+        let cdns = resp
+            .pointer("/data/cdnsWithName")
+            .and_then(|it| it.as_array())
+            .map(|it| {
+                it.iter().filter_map(|c| c.pointer("/cdn").and_then(|c| c.as_str())).map(|c| c.to_string()).collect()
+            })
+            .unwrap_or_else(Vec::new);
+        // rate 0 is the source
+        let rates = resp
+            .pointer("/data/multirates")
+            .and_then(|it| it.as_array())
+            .map(|it| {
+                it.iter()
+                    .map(|r| {
+                        let name = r.pointer("/name").and_then(|it| it.as_str()).unwrap_or("");
+                        (
+                            tier_height(name),
+                            r.pointer("/rate").and_then(|it| it.as_u64()).unwrap_or(0),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+        Ok(H5Play { cand: ret, cdns, rates })
     }
-    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<HashMap<String, String>> {
-        let mut ret = HashMap::new();
+
+    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<(String, Vec<StreamCandidate>)> {
         let rid = url::Url::parse(room_url)?
             .path_segments()
             .ok_or_else(|| dmlerr!())?
@@ -126,30 +188,28 @@ impl Douyu {
         ));
         param1.push(("did", did));
         param1.push(("tt", tsec));
-        param1.push(("cdn", "".to_string()));
         param1.push(("iar", "0".to_string()));
         param1.push(("ive", "0".to_string()));
-        param1.push(("rate", "0".to_string()));
         // println!("{:?}", &param1);
 
-        let resp = client
-            .post(format!("{}{}", DOUYU_API3, &rid))
-            .header("User-Agent", crate::utils::gen_ua())
-            .header("Referer", "https://www.douyu.com/")
-            .form(&param1)
-            .send()
-            .await?
-            .json::<serde_json::Value>()
-            .await?;
-        // println!("{:?}", &resp);
-        ret.insert(
-            String::from("url"),
-            format!(
-                "{}/{}",
-                resp.pointer("/data/rtmp_url").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
-                resp.pointer("/data/rtmp_live").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?
-            ),
-        );
+        let mut first = Self::get_h5_play(&client, &rid, &param1, "", 0).await?;
+        let rate = match self.cm.quality.get() {
+            Quality::Source => 0,
+            it => it.pick(&first.rates, |it| (it.0, 0)).map(|it| it.1).unwrap_or(0),
+        };
+        if rate != 0 {
+            first = Self::get_h5_play(&client, &rid, &param1, "", rate).await?;
+        }
+        let first_cdn = first.cand.cdn.clone();
+        let cdns = first.cdns;
+        let mut cands = vec![first.cand];
+        // the other cdns are only backups, a failed request should not fail the room
+        for cdn in cdns.iter().filter(|it| it.as_str() != first_cdn) {
+            match Self::get_h5_play(&client, &rid, &param1, cdn, rate).await {
+                Ok(it) => cands.push(it.cand),
+                Err(e) => info!("douyu cdn {} error: {}", cdn, e),
+            }
+        }
         let resp = client
             .get(format!("{}{}", DOUYU_API1, &rid))
             .header("User-Agent", crate::utils::gen_ua())
@@ -158,15 +218,20 @@ impl Douyu {
             .await?
             .json::<serde_json::Value>()
             .await?;
-        ret.insert(
-            String::from("title"),
-            format!(
-                "{} - {}",
-                resp.pointer("/room/room_name").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
-                resp.pointer("/room/nickname").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?
-            ),
+        let nickname = resp.pointer("/room/nickname").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?;
+        let title = format!(
+            "{} - {}",
+            resp.pointer("/room/room_name").ok_or_else(|| dmlerr!())?.as_str().ok_or_else(|| dmlerr!())?,
+            nickname
         );
+        *self.cm.streamer.borrow_mut() = nickname.to_string();
+        *self.cm.cover_url.borrow_mut() = resp
+            .pointer("/room/room_pic")
+            .or_else(|| resp.pointer("/room/avatar/big"))
+            .and_then(|it| it.as_str())
+            .unwrap_or("")
+            .to_string();
 
-        Ok(ret)
+        Ok((title, cands))
     }
 }
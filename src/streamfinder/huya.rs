@@ -1,20 +1,25 @@
 use base64::{engine::general_purpose, Engine};
 use regex::Regex;
-use std::{collections::HashMap, str};
+use std::{collections::HashMap, rc::Rc, str};
 use url::form_urlencoded;
 
-use crate::dmlerr;
+use super::{tier_height, StreamCandidate};
+use crate::{
+    config::{ConfigManager, Quality},
+    dmlerr,
+};
 
-pub struct Huya {}
+pub struct Huya {
+    cm: Rc<ConfigManager>,
+}
 
 impl Huya {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(cm: Rc<ConfigManager>) -> Self {
+        Self { cm }
     }
 
-    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<HashMap<String, String>> {
+    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<(String, Vec<StreamCandidate>)> {
         let client = reqwest::Client::new();
-        let mut ret = HashMap::new();
         let resp = client
             .get(room_url)
             .header("User-Agent", crate::utils::gen_ua())
@@ -30,35 +35,69 @@ impl Huya {
         let j1: serde_json::Value = serde_json::from_str(&re1.captures(&resp).ok_or_else(|| dmlerr!())?[1])?;
         let j2: serde_json::Value = serde_json::from_str(&re2.captures(&resp).ok_or_else(|| dmlerr!())?[1])?;
         // println!("{:?}", &j);
-        ret.insert(
-            String::from("title"),
-            format!(
-                "{} - {}",
-                j2.pointer("/introduction").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                j1.pointer("/nick").ok_or_else(|| dmlerr!())?.as_str().unwrap()
-            ),
+        let nick = j1.pointer("/nick").ok_or_else(|| dmlerr!())?.as_str().unwrap();
+        let title = format!(
+            "{} - {}",
+            j2.pointer("/introduction").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
+            nick
         );
-        let flv_anti_code =
-            j.pointer("/data/0/gameStreamInfoList/0/sFlvAntiCode").ok_or_else(|| dmlerr!())?.as_str().unwrap();
-        let stream_name =
-            j.pointer("/data/0/gameStreamInfoList/0/sStreamName").ok_or_else(|| dmlerr!())?.as_str().unwrap();
-        let p = Self::gen_params(flv_anti_code, stream_name);
-        ret.insert(
-            String::from("url"),
-            html_escape::decode_html_entities(
-                format!(
-                    "{}/{}.{}?{}",
-                    j.pointer("/data/0/gameStreamInfoList/0/sFlvUrl").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                    stream_name,
-                    j.pointer("/data/0/gameStreamInfoList/0/sFlvUrlSuffix").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
-                    p,
+        *self.cm.streamer.borrow_mut() = nick.to_string();
+        *self.cm.cover_url.borrow_mut() = j2
+            .pointer("/screenshot")
+            .or_else(|| j1.pointer("/avatar"))
+            .and_then(|it| it.as_str())
+            .unwrap_or("")
+            .to_string();
+        // ratio 0 is the source
+        let rates: Vec<(u64, u64)> = j
+            .pointer("/vMultiStreamInfo")
+            .and_then(|it| it.as_array())
+            .map(|it| {
+                it.iter()
+                    .map(|r| {
+                        let name = r.pointer("/sDisplayName").and_then(|it| it.as_str()).unwrap_or("");
+                        (
+                            tier_height(name),
+                            r.pointer("/iBitRate").and_then(|it| it.as_u64()).unwrap_or(0),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let ratio = match self.cm.quality.get() {
+            Quality::Source => 0,
+            it => it.pick(&rates, |it| (it.0, 0)).map(|it| it.1).unwrap_or(0),
+        };
+        let mut cands = Vec::new();
+        let infos = j.pointer("/data/0/gameStreamInfoList").and_then(|it| it.as_array()).ok_or_else(|| dmlerr!())?;
+        // gameStreamInfoList is already sorted by the cdn priority of the web player
+        for info in infos {
+            let flv_anti_code = info.pointer("/sFlvAntiCode").ok_or_else(|| dmlerr!())?.as_str().unwrap();
+            let stream_name = info.pointer("/sStreamName").ok_or_else(|| dmlerr!())?.as_str().unwrap();
+            let mut p = Self::gen_params(flv_anti_code, stream_name);
+            if ratio > 0 {
+                p.push_str(&format!("&ratio={}", ratio));
+            }
+            cands.push(StreamCandidate {
+                url: html_escape::decode_html_entities(
+                    format!(
+                        "{}/{}.{}?{}",
+                        info.pointer("/sFlvUrl").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
+                        stream_name,
+                        info.pointer("/sFlvUrlSuffix").ok_or_else(|| dmlerr!())?.as_str().unwrap(),
+                        p,
+                    )
+                    .as_str(),
                 )
-                .as_str(),
-            )
-            .to_string(),
-        );
+                .to_string(),
+                protocol: "flv".into(),
+                codec: "".into(),
+                quality: ratio.to_string(),
+                cdn: info.pointer("/sCdnType").and_then(|it| it.as_str()).unwrap_or("").to_string(),
+            });
+        }
 
-        Ok(ret)
+        Ok((title, cands))
     }
 
     fn gen_n_number(l: u8) -> String {
@@ -12,6 +12,42 @@ use log::info;
 use log::warn;
 use std::rc::Rc;
 
+#[derive(Debug, Clone)]
+pub struct StreamCandidate {
+    pub url: String,
+    pub protocol: String,
+    pub codec: String,
+    pub quality: String,
+    pub cdn: String,
+}
+
+impl StreamCandidate {
+    pub fn new(url: String) -> Self {
+        StreamCandidate {
+            url,
+            protocol: "".into(),
+            codec: "".into(),
+            quality: "".into(),
+            cdn: "".into(),
+        }
+    }
+}
+
+// douyu and huya only give their tiers a name
+pub fn tier_height(name: &str) -> u64 {
+    if name.contains("4K") || name.contains("2160") {
+        2160
+    } else if name.contains("原画") || name.contains("蓝光") || name.contains("1080") {
+        1080
+    } else if name.contains("超清") || name.contains("720") {
+        720
+    } else if name.contains("高清") || name.contains("540") {
+        540
+    } else {
+        360
+    }
+}
+
 #[allow(unused)]
 pub struct StreamFinder {
     ipc_manager: Rc<IPCManager>,
@@ -34,6 +70,22 @@ impl StreamFinder {
     //     Ok((u.remove(0), u))
     // }
 
+    // candidates are ranked by the site finders, only those the streamer can switch to in place are kept
+    fn rank_candidates(cands: Vec<StreamCandidate>) -> Result<Vec<String>> {
+        let first = cands.first().ok_or_else(|| anyhow!("no stream candidate"))?.clone();
+        let mut ret = Vec::new();
+        for (i, c) in cands.into_iter().enumerate() {
+            info!(
+                "candidate {}: {} {} {} {} {}",
+                i, &c.protocol, &c.codec, &c.quality, &c.cdn, &c.url
+            );
+            if c.protocol.eq(&first.protocol) && c.codec.eq(&first.codec) && !ret.contains(&c.url) {
+                ret.push(c.url);
+            }
+        }
+        Ok(ret)
+    }
+
     pub async fn run(&self) -> Result<(String, Vec<String>)> {
         loop {
             for _ in 0..20 {
@@ -41,8 +93,8 @@ impl StreamFinder {
                     crate::config::Site::BiliLive => {
                         let b = bilibili::Bilibili::new(self.cm.clone());
                         match b.get_live(&self.cm.room_url).await {
-                            Ok(u) => {
-                                return Ok((u["title"].to_string(), vec![u["url"].to_string()]));
+                            Ok((title, cands)) => {
+                                return Ok((title, Self::rank_candidates(cands)?));
                             }
                             Err(e) => {
                                 info!("{}", e);
@@ -62,10 +114,10 @@ impl StreamFinder {
                         };
                     }
                     crate::config::Site::DouyuLive => {
-                        let b = douyu::Douyu::new();
+                        let b = douyu::Douyu::new(self.cm.clone());
                         match b.get_live(&self.cm.room_url).await {
-                            Ok(u) => {
-                                return Ok((u["title"].to_string(), vec![u["url"].to_string()]));
+                            Ok((title, cands)) => {
+                                return Ok((title, Self::rank_candidates(cands)?));
                             }
                             Err(e) => {
                                 info!("{}", e);
@@ -73,10 +125,10 @@ impl StreamFinder {
                         };
                     }
                     crate::config::Site::HuyaLive => {
-                        let b = huya::Huya::new();
+                        let b = huya::Huya::new(self.cm.clone());
                         match b.get_live(&self.cm.room_url).await {
-                            Ok(u) => {
-                                return Ok((u["title"].to_string(), vec![u["url"].to_string()]));
+                            Ok((title, cands)) => {
+                                return Ok((title, Self::rank_candidates(cands)?));
                             }
                             Err(e) => {
                                 info!("{}", e);
@@ -84,10 +136,10 @@ impl StreamFinder {
                         };
                     }
                     crate::config::Site::TwitchLive => {
-                        let b = twitch::Twitch::new();
+                        let b = twitch::Twitch::new(self.cm.clone());
                         match b.get_live(&self.cm.room_url).await {
-                            Ok(u) => {
-                                return Ok((u["title"].to_string(), vec![u["url"].to_string()]));
+                            Ok((title, cands)) => {
+                                return Ok((title, Self::rank_candidates(cands)?));
                             }
                             Err(e) => {
                                 info!("{}", e);
@@ -95,11 +147,10 @@ impl StreamFinder {
                         };
                     }
                     crate::config::Site::YoutubeLive => {
-                        let b = youtube::Youtube::new();
+                        let b = youtube::Youtube::new(self.cm.clone());
                         match b.get_live(&self.cm.room_url).await {
-                            Ok(u) => {
-                                let a: Vec<String> = u["url"].split('\n').map(|x| x.to_string()).collect();
-                                return Ok((u["title"].to_string(), a));
+                            Ok((title, cands)) => {
+                                return Ok((title, Self::rank_candidates(cands)?));
                             }
                             Err(e) => {
                                 info!("{}", e);
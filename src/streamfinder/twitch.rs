@@ -1,27 +1,80 @@
-use crate::dmlerr;
+use super::StreamCandidate;
+use crate::{
+    config::{ConfigManager, Quality},
+    dmlerr,
+    utils::normalize_codec,
+};
 use regex::Regex;
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 use url::Url;
 
 const TTV_API1: &'static str = "https://gql.twitch.tv/gql";
 const TTV_API2: &'static str = "https://usher.ttvnw.net/api/channel/hls/{channel}.m3u8";
 
+struct Variant {
+    url: String,
+    codec: String,
+    height: u64,
+    fps: u64,
+    source: bool,
+    audio_only: bool,
+}
+
 pub struct Twitch {
+    cm: Rc<ConfigManager>,
 }
 
 impl Twitch {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(cm: Rc<ConfigManager>) -> Self {
+        Self { cm }
+    }
+
+    fn decode_variants(m3u8_text: &str) -> Vec<Variant> {
+        let mut ret = Vec::new();
+        let mut inf: Option<&str> = None;
+        for line in m3u8_text.lines().map(|it| it.trim()) {
+            if let Some(it) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+                inf = Some(it);
+            } else if line.starts_with("http") {
+                let inf = match inf.take() {
+                    Some(it) => it,
+                    None => continue,
+                };
+                let attr = |k: &str| {
+                    inf.split(',').find_map(|it| it.strip_prefix(k)).map(|it| it.trim_matches('"').to_string())
+                };
+                let video = attr("VIDEO=").unwrap_or_default();
+                let height =
+                    attr("RESOLUTION=").and_then(|it| it.split_once('x').and_then(|it| it.1.parse().ok())).unwrap_or(0);
+                if height == 0 && video.ne("audio_only") {
+                    continue;
+                }
+                ret.push(Variant {
+                    url: line.to_string(),
+                    // the video codec comes first in CODECS
+                    codec: attr("CODECS=").unwrap_or_default(),
+                    height,
+                    fps: attr("FRAME-RATE=").and_then(|it| it.parse::<f64>().ok()).unwrap_or(0.0).round() as u64,
+                    source: video.eq("chunked"),
+                    audio_only: video.eq("audio_only"),
+                });
+            }
         }
+        ret
     }
 
-    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<HashMap<String, String>> {
+    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<(String, Vec<StreamCandidate>)> {
         let rid = Url::parse(room_url)?
             .path_segments()
             .ok_or_else(|| dmlerr!())?
             .last()
             .ok_or_else(|| dmlerr!())?
             .to_string();
+        *self.cm.streamer.borrow_mut() = rid.clone();
+        *self.cm.cover_url.borrow_mut() = format!(
+            "https://static-cdn.jtvnw.net/previews-ttv/live_user_{}-1280x720.jpg",
+            rid.to_lowercase()
+        );
         let client = reqwest::Client::new();
         let mut ret = HashMap::new();
         let resp = client
@@ -65,11 +118,30 @@ impl Twitch {
             .ok_or_else(|| dmlerr!())?
             .as_str()
             .ok_or_else(|| dmlerr!())?;
+        let mut codecs: Vec<&str> = self
+            .cm
+            .codec_preference
+            .iter()
+            .filter_map(|it| match it.as_str() {
+                "avc" => Some("h264"),
+                "hevc" => Some("h265"),
+                "av1" => Some("av1"),
+                // twitch has nothing else, an unknown codec must not turn into one of these
+                _ => None,
+            })
+            .collect();
+        if !codecs.is_empty() && !codecs.contains(&"h264") {
+            codecs.push("h264");
+        }
+        let supported_codecs = codecs.join(",");
         param1.clear();
         param1.push(("allow_source", "true"));
         param1.push(("fast_bread", "true"));
         param1.push(("sig", sign));
         param1.push(("token", token));
+        if !supported_codecs.is_empty() {
+            param1.push(("supported_codecs", &supported_codecs));
+        }
         let api2 = TTV_API2.replace("{channel}", &rid);
         let resp = client
             .get(api2)
@@ -83,11 +155,26 @@ impl Twitch {
             .await?;
 
         // println!("{}", &resp);
-        let re = Regex::new(r#"[\s\S]+?\n(http[^\n]+)"#).unwrap();
-        ret.insert(
-            "url".to_string(),
-            re.captures(&resp).ok_or_else(|| dmlerr!())?.get(1).ok_or_else(|| dmlerr!())?.as_str().to_string(),
-        );
-        Ok(ret)
+        let mut variants = Self::decode_variants(&resp);
+        if let Some(v) = variants.iter().find(|it| it.audio_only && self.cm.audio_only) {
+            let mut c = StreamCandidate::new(v.url.clone());
+            c.protocol = "hls".into();
+            c.quality = "audio_only".into();
+            return Ok((ret["title"].to_string(), vec![c]));
+        }
+        variants.retain(|it| !it.audio_only);
+        let rank = variants.iter().map(|it| self.cm.codec_rank(&it.codec)).min().unwrap_or(0);
+        variants.retain(|it| self.cm.codec_rank(&it.codec) == rank);
+        let v = match self.cm.quality.get() {
+            Quality::Source => variants.iter().find(|it| it.source),
+            it => it.pick(&variants, |it| (it.height, it.fps)),
+        }
+        .or_else(|| variants.first())
+        .ok_or_else(|| dmlerr!())?;
+        let mut c = StreamCandidate::new(v.url.clone());
+        c.protocol = "hls".into();
+        c.codec = normalize_codec(&v.codec).to_string();
+        c.quality = format!("{}p{}", v.height, v.fps);
+        Ok((ret["title"].to_string(), vec![c]))
     }
 }
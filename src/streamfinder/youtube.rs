@@ -1,92 +1,18 @@
-use log::{debug, info};
+use log::info;
 use regex::Regex;
 use reqwest::Client;
-use std::collections::HashMap;
 
-use crate::{dmlerr, utils};
+use super::StreamCandidate;
+use crate::{config::ConfigManager, dmlerr, utils};
+use std::rc::Rc;
 
-pub struct Youtube {}
+pub struct Youtube {
+    cm: Rc<ConfigManager>,
+}
 
 impl Youtube {
-    pub fn new() -> Self {
-        Youtube {}
-    }
-
-    #[allow(dead_code)]
-    async fn decode_mpd(&self, client: &Client, url: &str) -> anyhow::Result<String> {
-        info!("{}", url);
-        let mut dash_urls = "".to_owned();
-        let mut video_base_url = Vec::new();
-        let mut audio_base_url = None;
-        let mut sq = 0;
-        let resp = client
-            .get(url)
-            .header("User-Agent", crate::utils::gen_ua())
-            .header("Accept-Language", "en-US")
-            .header("Referer", "https://www.youtube.com/")
-            .send()
-            .await?
-            .text()
-            .await?;
-        let doc = roxmltree::Document::parse(resp.as_str())?;
-        let elem_vs: Vec<roxmltree::Node> = doc
-            .descendants()
-            .filter(|n| {
-                n.tag_name().name() == "AdaptationSet" && n.attribute("mimeType").unwrap_or("").contains("video")
-            })
-            .collect();
-        let mut tmpnode = None;
-        for elem_v in elem_vs {
-            let mut url = None;
-            for st in elem_v.descendants() {
-                if st.has_attribute("bandwidth") {
-                    debug!("{:?}", &st);
-                    for e in st.descendants() {
-                        if e.tag_name().name().eq("BaseURL") {
-                            url = e.text();
-                        }
-                    }
-                }
-            }
-            if url.is_some() {
-                video_base_url.push(url.unwrap());
-            }
-        }
-        let elem_a = doc
-            .descendants()
-            .find(|n| n.tag_name().name() == "AdaptationSet" && n.attribute("mimeType").unwrap_or("").contains("audio"))
-            .unwrap();
-        for e in elem_a.descendants() {
-            if e.has_attribute("bandwidth") {
-                tmpnode = Some(e);
-            }
-        }
-        for e in tmpnode.unwrap().descendants() {
-            if e.tag_name().name().eq("SegmentList") {
-                for seg in e.descendants() {
-                    if seg.tag_name().name().eq("SegmentURL") {
-                        let spl: Vec<_> = seg.attribute("media").unwrap_or("").split('/').collect();
-                        sq = spl[1].parse()?;
-                    }
-                }
-            }
-            if e.tag_name().name().eq("BaseURL") {
-                audio_base_url = e.text();
-            }
-        }
-
-        if !video_base_url.is_empty() && audio_base_url.is_some() {
-            dash_urls.push_str(video_base_url.last().unwrap());
-            dash_urls.push('\n');
-            dash_urls.push_str(audio_base_url.unwrap());
-            dash_urls.push('\n');
-            dash_urls.push_str(format!("{}", &sq).as_str());
-        }
-        if dash_urls.is_empty() {
-            Err(anyhow::anyhow!("no dash url found"))
-        } else {
-            Ok(dash_urls)
-        }
+    pub fn new(cm: Rc<ConfigManager>) -> Self {
+        Youtube { cm }
     }
 
     #[allow(dead_code)]
@@ -114,7 +40,7 @@ impl Youtube {
         }
     }
 
-    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<HashMap<String, String>> {
+    pub async fn get_live(&self, room_url: &str) -> anyhow::Result<(String, Vec<StreamCandidate>)> {
         let client = reqwest::Client::builder()
             .user_agent(utils::gen_ua())
             .timeout(tokio::time::Duration::from_secs(10))
@@ -150,15 +76,26 @@ impl Youtube {
         if !(j.pointer("/videoDetails/isLive").ok_or_else(|| dmlerr!())?.as_bool().unwrap()) {
             return Err(anyhow::anyhow!("not on air!"));
         }
+        if let Some(it) = j.pointer("/videoDetails/author").and_then(|it| it.as_str()) {
+            *self.cm.streamer.borrow_mut() = it.to_string();
+        }
+        // the last thumbnail is the largest
+        if let Some(it) = j
+            .pointer("/videoDetails/thumbnail/thumbnails")
+            .and_then(|it| it.as_array())
+            .and_then(|it| it.last())
+            .and_then(|it| it.pointer("/url"))
+            .and_then(|it| it.as_str())
+        {
+            *self.cm.cover_url.borrow_mut() = it.to_string();
+        }
         let mpd_url = j.pointer("/streamingData/dashManifestUrl").ok_or_else(|| dmlerr!())?.as_str().unwrap();
         // let hls_url = j.pointer("/streamingData/hlsManifestUrl").ok_or_else(|| dmlerr!())?.as_str().unwrap();
 
         // let urls = self.decode_m3u8(&client, &hls_url).await?;
-        let urls = self.decode_mpd(&client, &mpd_url).await?;
 
-        let mut ret = HashMap::new();
-        ret.insert(String::from("url"), urls);
-        ret.insert(String::from("title"), title);
-        Ok(ret)
+        let mut c = StreamCandidate::new(mpd_url.to_string());
+        c.protocol = "dash".into();
+        Ok((title, vec![c]))
     }
 }
@@ -122,6 +122,97 @@ pub fn nm(a: u64, ary: u64) -> Vec<u8> {
     tp(0, a, &vn(ary))
 }
 
+// "90", "90s", "10m", "1h30m" to seconds
+pub fn parse_duration_secs(s: &str) -> Option<u64> {
+    let s = s.trim();
+    if let Ok(it) = s.parse() {
+        return Some(it);
+    }
+    let mut ret = 0u64;
+    let mut num = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue;
+        }
+        let n: u64 = num.parse().ok()?;
+        num.clear();
+        ret += match c.to_ascii_lowercase() {
+            'h' => n * 3600,
+            'm' => n * 60,
+            's' => n,
+            _ => return None,
+        };
+    }
+    (!s.is_empty() && num.is_empty()).then_some(ret)
+}
+
+// "512MB", "2GiB", "4G", "800kb" to bytes, the unit is always binary
+pub fn parse_size_bytes(s: &str) -> Option<u64> {
+    let s = s.trim().to_uppercase();
+    let s = s.strip_suffix('B').unwrap_or(&s);
+    let s = s.strip_suffix('I').unwrap_or(s);
+    let (num, mul) = match s.chars().last()? {
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    num.trim().parse::<u64>().ok().and_then(|it| it.checked_mul(mul))
+}
+
+// the longest prefix of at most `max` bytes that ends on a char boundary
+pub fn truncate_bytes(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// replaces what windows, macos or linux refuse in a file name
+pub fn sanitize_filename(s: &str) -> String {
+    let ret: String = s
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect();
+    ret.trim().trim_end_matches('.').to_string()
+}
+
+// "avc1.640028", "h264", "hev1.1.6.L120" and friends to avc, hevc or av1
+pub fn normalize_codec(codec: &str) -> &'static str {
+    let c = codec.trim().to_lowercase();
+    if c.starts_with("avc") || c.starts_with("h264") {
+        "avc"
+    } else if c.starts_with("hevc") || c.starts_with("hev1") || c.starts_with("hvc1") || c.starts_with("h265") {
+        "hevc"
+    } else if c.starts_with("av1") || c.starts_with("av01") {
+        "av1"
+    } else {
+        ""
+    }
+}
+
+// unix time the signed stream url stops working, bilibili uses decimal, huya and douyu use hex
+pub fn url_expire_time(url: &str) -> Option<i64> {
+    let u = url::Url::parse(url).ok()?;
+    for (k, v) in u.query_pairs() {
+        match k.as_ref() {
+            "expires" => return v.parse().ok(),
+            "wsTime" | "txTime" => return i64::from_str_radix(&v, 16).ok(),
+            _ => {}
+        }
+    }
+    None
+}
+
 pub fn _str_to_ms(time_str: &str) -> u64 {
     let mut t = time_str.trim().rsplit(':');
     let mut ret = 0f64;
@@ -156,3 +247,68 @@ pub async fn is_android() -> bool {
     };
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration_secs("90"), Some(90));
+        assert_eq!(parse_duration_secs(" 90s "), Some(90));
+        assert_eq!(parse_duration_secs("10m"), Some(600));
+        assert_eq!(parse_duration_secs("1h30m"), Some(5400));
+        assert_eq!(parse_duration_secs("1H2M3S"), Some(3723));
+        assert_eq!(parse_duration_secs("0"), Some(0));
+        assert_eq!(parse_duration_secs(""), None);
+        assert_eq!(parse_duration_secs("m"), None);
+        assert_eq!(parse_duration_secs("1h30"), None);
+        assert_eq!(parse_duration_secs("10d"), None);
+        assert_eq!(parse_duration_secs("-5"), None);
+        assert_eq!(parse_duration_secs("1.5m"), None);
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size_bytes("1024"), Some(1024));
+        assert_eq!(parse_size_bytes("800kb"), Some(800 << 10));
+        assert_eq!(parse_size_bytes("512MB"), Some(512 << 20));
+        assert_eq!(parse_size_bytes("2GiB"), Some(2 << 30));
+        assert_eq!(parse_size_bytes(" 4 G "), Some(4 << 30));
+        assert_eq!(parse_size_bytes("100B"), Some(100));
+        assert_eq!(parse_size_bytes(""), None);
+        assert_eq!(parse_size_bytes("MB"), None);
+        assert_eq!(parse_size_bytes("1.5G"), None);
+        assert_eq!(parse_size_bytes("2TB"), None);
+        // too large for u64 is invalid, not an overflow
+        assert_eq!(parse_size_bytes("99999999999G"), None);
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        assert_eq!(truncate_bytes("title", 10), "title");
+        assert_eq!(truncate_bytes("title", 5), "title");
+        assert_eq!(truncate_bytes("title", 2), "ti");
+        assert_eq!(truncate_bytes("title", 0), "");
+        // three bytes each
+        assert_eq!(truncate_bytes("直播间", 7), "直播");
+        assert_eq!(truncate_bytes("直播间", 2), "");
+        assert_eq!(truncate_bytes("a😀b", 4), "a");
+        assert_eq!(truncate_bytes("a😀b", 5), "a😀");
+    }
+
+    #[test]
+    fn sanitizes_filenames() {
+        assert_eq!(sanitize_filename("a/b\\c"), "a-b-c");
+        assert_eq!(
+            sanitize_filename("what? <live> \"now\": *|*"),
+            "what- -live- -now-- ---"
+        );
+        assert_eq!(sanitize_filename("line\nbreak\ttab"), "line break tab");
+        assert_eq!(sanitize_filename("  padded  "), "padded");
+        assert_eq!(sanitize_filename("ends with dots..."), "ends with dots");
+        assert_eq!(sanitize_filename(".."), "");
+        assert_eq!(sanitize_filename(".hidden"), ".hidden");
+        assert_eq!(sanitize_filename("【直播】游戏 🎮"), "【直播】游戏 🎮");
+    }
+}